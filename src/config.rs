@@ -0,0 +1,90 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{datacube, image, imzml, mass, render, roi, stage};
+
+/// everything needed to reproduce the processing of a run; can be read from a json file and is
+/// written next to the outputs (with the calibration that was actually used)
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RunConfig {
+    pub image: image::Config,
+    pub calibrants: Vec<mass::Calibrant>, // if not empty, image.calibration is fitted to these
+    pub calibration_model: mass::CalibrationModel,
+    pub reference_masses: Vec<f64>, // if not empty, found peaks are calibrated against these m/z
+    pub reference_tolerance: f64,   // in Da, for matching references to peaks
+    pub rois: Vec<roi::Roi>,        // if not empty, a spectrum is accumulated for each region
+    pub mass_list: Option<std::path::PathBuf>, // ion images to make in addition to the found peaks
+    pub presentation: render::Presentation,     // color versions of the images
+    pub ome_tiff: bool,                         // also save the tic and ion images as one OME-TIFF stack
+    pub imzml: Option<imzml::ImzmlConfig>,      // if set, the events are also converted to imzML
+    pub datacube: Option<datacube::DatacubeConfig>, // if set, the events are also binned into a Zarr datacube
+    pub stage_log: Option<stage::StageLog>,     // if set, the coordinates are read from it instead of inferred
+}
+
+impl Default for RunConfig {
+    fn default() -> RunConfig {
+        RunConfig {
+            image: image::Config::default(),
+            calibrants: vec![],
+            calibration_model: mass::CalibrationModel::Physical,
+            reference_masses: vec![],
+            reference_tolerance: 0.5,
+            rois: vec![],
+            mass_list: None,
+            presentation: render::Presentation::default(),
+            ome_tiff: false,
+            imzml: None,
+            datacube: None,
+            stage_log: None,
+        }
+    }
+}
+
+impl RunConfig {
+    pub fn load(path: &std::path::Path) -> Result<RunConfig, Box<dyn Error>> {
+        let mut run_config: RunConfig = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        run_config.image.update(); // memoized values are not stored
+        run_config.validate()?;
+        Ok(run_config)
+    }
+
+    /// checks the settings serde accepts but the processing cannot use
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.image.spectrum.validate()?;
+        self.image.peak_picking.validate()
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// fits the mass calibration to the calibrants (if any) and reports the residuals
+    pub fn calibrate(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.calibrants.is_empty() {
+            return Ok(());
+        }
+        let calibration = mass::MassCalibration::fit(self.calibration_model, &self.calibrants)?;
+        println!("fitted calibration: {:?}", calibration);
+        for c in self.calibrants.iter() {
+            let mz = calibration.time_to_mass(c.tof);
+            println!("  calibrant {} m/z at {} ps: {:.4} ({:+.1} mDa)", c.mz, c.tof, mz, (mz - c.mz) * 1000.0);
+        }
+        self.image.calibration = calibration;
+        Ok(())
+    }
+
+    /// calibrates against the reference masses (if any) using the found peaks
+    pub fn auto_calibrate(&mut self, peaks: &[mass::Peak]) -> Result<Option<mass::CalibrationReport>, Box<dyn Error>> {
+        if self.reference_masses.is_empty() {
+            return Ok(None);
+        }
+        let report =
+            mass::auto_calibrate(peaks, &self.reference_masses, self.calibration_model, self.reference_tolerance)?;
+        println!("automatic calibration: {:?}, {:.1} ppm rms", report.calibration, report.rms_ppm());
+        self.image.calibration = report.calibration;
+        Ok(Some(report))
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use nohash_hasher::IntMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    hit::Hit,
+    image, mass,
+    reader::TPX3Reader,
+    stage::{Coord, Direction},
+};
+
+/// the chunk shape of the datacube (image rows x columns x spectrum bins) and the zlib level of
+/// its chunks; a band of `chunk_rows` rows is held in memory (sparsely) until the stage has left it
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatacubeConfig {
+    pub chunk_rows: usize,
+    pub chunk_cols: usize,
+    pub chunk_bins: usize,
+    pub level: u32, // 0-9
+}
+
+impl Default for DatacubeConfig {
+    fn default() -> DatacubeConfig { DatacubeConfig { chunk_rows: 16, chunk_cols: 64, chunk_bins: 1024, level: 1 } }
+}
+
+/// the counts of one band of rows, keyed by (row in band, col, bin) in C order
+struct Band {
+    counts: IntMap<u64, u32>,
+    added: bool,
+    empty_pass_count: usize,
+}
+
+/// a rows x cols x bins array of u32 counts written chunk by chunk to a Zarr (v2) directory store;
+/// chunks without counts are never written and read as the fill value 0
+pub struct ChunkedCube {
+    dir: PathBuf,
+    shape: [usize; 3],
+    config: DatacubeConfig,
+    bands: HashMap<usize, Band>, // key is the band index (row / chunk_rows)
+    pub chunks_written: usize,
+    pub peak_counts_held: usize, // the most (pixel, bin) counts held in memory at once
+}
+
+impl ChunkedCube {
+    /// creates the array directory `dir` (e.g., "<store>/intensities") and its metadata
+    pub fn create(dir: &Path, shape: [usize; 3], config: DatacubeConfig) -> Result<ChunkedCube, Box<dyn Error>> {
+        let chunks = [config.chunk_rows, config.chunk_cols, config.chunk_bins];
+        if chunks.contains(&0) {
+            return Err("datacube chunks can not be empty".into());
+        }
+        let compressor = json!({"id": "zlib", "level": config.level});
+        write_array_metadata(dir, &shape, &chunks, "<u4", compressor, &["y", "x", "bin"])?;
+        let dir = dir.to_path_buf();
+        Ok(ChunkedCube { dir, shape, config, bands: HashMap::new(), chunks_written: 0, peak_counts_held: 0 })
+    }
+
+    /// counts one event; events outside of the array are dropped
+    pub fn add(&mut self, row: usize, col: usize, bin: usize) {
+        let [rows, cols, bins] = self.shape;
+        if row >= rows || col >= cols || bin >= bins {
+            return;
+        }
+        let chunk_rows = self.config.chunk_rows;
+        let band = self.bands.entry(row / chunk_rows).or_insert_with(|| Band {
+            counts: IntMap::default(),
+            added: false,
+            empty_pass_count: 0,
+        });
+        band.added = true;
+        let key = (((row % chunk_rows) * cols + col) * bins + bin) as u64;
+        let count = band.counts.entry(key).or_insert(0);
+        *count = count.saturating_add(1);
+    }
+
+    /// called when the stage turns; bands that have not been added to for more than two passes
+    /// are written out and dropped from memory
+    pub fn end_pass(&mut self) -> Result<(), Box<dyn Error>> {
+        self.note_memory();
+        for band in self.bands.values_mut() {
+            band.empty_pass_count = if band.added { 0 } else { band.empty_pass_count + 1 };
+            band.added = false;
+        }
+        let mut finished: Vec<usize> =
+            self.bands.iter().filter(|(_, b)| b.empty_pass_count > 2).map(|(&k, _)| k).collect();
+        finished.sort_unstable();
+        finished.into_iter().try_for_each(|band| self.write_band(band))
+    }
+
+    /// writes the remaining bands
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.note_memory();
+        let mut remaining: Vec<usize> = self.bands.keys().copied().collect();
+        remaining.sort_unstable();
+        remaining.into_iter().try_for_each(|band| self.write_band(band))
+    }
+
+    /// the number of chunks the array is made of (written or not)
+    pub fn chunk_count(&self) -> usize {
+        let chunks = [self.config.chunk_rows, self.config.chunk_cols, self.config.chunk_bins];
+        self.shape.iter().zip(chunks).map(|(n, c)| n.div_ceil(c)).product()
+    }
+
+    fn note_memory(&mut self) {
+        let held = self.bands.values().map(|b| b.counts.len()).sum();
+        self.peak_counts_held = self.peak_counts_held.max(held);
+    }
+
+    /// splits a band into its chunks; a chunk that was already written (the stage came back to
+    /// rows thought finished) is read and added to rather than replaced
+    fn write_band(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        let band = match self.bands.remove(&index) {
+            Some(band) => band,
+            None => return Ok(()),
+        };
+        let [_, cols, bins] = self.shape;
+        let (cr, cc, cb) = (self.config.chunk_rows, self.config.chunk_cols, self.config.chunk_bins);
+        let mut chunks: HashMap<(usize, usize), Vec<(usize, u32)>> = HashMap::new();
+        for (key, count) in band.counts {
+            let key = key as usize;
+            let (bin, col, row) = (key % bins, key / bins % cols, key / bins / cols);
+            let offset = (row * cc + col % cc) * cb + bin % cb;
+            chunks.entry((col / cc, bin / cb)).or_default().push((offset, count));
+        }
+        let mut keys: Vec<(usize, usize)> = chunks.keys().copied().collect();
+        keys.sort_unstable();
+        for (col_chunk, bin_chunk) in keys {
+            let chunk_index = [index, col_chunk, bin_chunk];
+            let mut values = match read_chunk(&self.dir, chunk_index, [cr, cc, cb])? {
+                Some(values) => values,
+                None => vec![0u32; cr * cc * cb],
+            };
+            for &(offset, count) in chunks[&(col_chunk, bin_chunk)].iter() {
+                values[offset] = values[offset].saturating_add(count);
+            }
+            let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+            let mut encoder = ZlibEncoder::new(vec![], Compression::new(self.config.level));
+            encoder.write_all(&bytes)?;
+            std::fs::write(self.dir.join(chunk_key(chunk_index)), encoder.finish()?)?;
+            self.chunks_written += 1;
+        }
+        Ok(())
+    }
+}
+
+fn chunk_key(index: [usize; 3]) -> String { format!("{}.{}.{}", index[0], index[1], index[2]) }
+
+/// the values of a chunk of a zlib compressed u32 array, or None if it was never written (all 0)
+pub fn read_chunk(dir: &Path, index: [usize; 3], chunks: [usize; 3]) -> Result<Option<Vec<u32>>, Box<dyn Error>> {
+    let path = dir.join(chunk_key(index));
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut bytes = vec![];
+    ZlibDecoder::new(std::fs::File::open(path)?).read_to_end(&mut bytes)?;
+    if bytes.len() != chunks.iter().product::<usize>() * 4 {
+        return Err(format!("chunk {} has {} bytes", chunk_key(index), bytes.len()).into());
+    }
+    Ok(Some(bytes.chunks(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()))
+}
+
+/// the .zarray (and the xarray style dimension names in .zattrs) of an array
+fn write_array_metadata(
+    dir: &Path, shape: &[usize], chunks: &[usize], dtype: &str, compressor: serde_json::Value,
+    dimensions: &[&str],
+) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(dir)?;
+    let zarray = json!({
+        "zarr_format": 2,
+        "shape": shape,
+        "chunks": chunks,
+        "dtype": dtype,
+        "compressor": compressor,
+        "fill_value": 0,
+        "order": "C",
+        "filters": null,
+        "dimension_separator": ".",
+    });
+    std::fs::write(dir.join(".zarray"), serde_json::to_string_pretty(&zarray)?)?;
+    std::fs::write(dir.join(".zattrs"), serde_json::to_string_pretty(&json!({ "_ARRAY_DIMENSIONS": dimensions }))?)?;
+    Ok(())
+}
+
+/// a one dimensional, uncompressed f64 coordinate array in a single chunk
+fn write_axis(dir: &Path, values: &[f64], dimension: &str, units: &str) -> Result<(), Box<dyn Error>> {
+    write_array_metadata(dir, &[values.len()], &[values.len().max(1)], "<f8", serde_json::Value::Null, &[dimension])?;
+    let attributes = json!({ "_ARRAY_DIMENSIONS": [dimension], "units": units });
+    std::fs::write(dir.join(".zattrs"), serde_json::to_string_pretty(&attributes)?)?;
+    std::fs::write(dir.join("0"), values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>())?;
+    Ok(())
+}
+
+/// a datacube being filled from the events of an image: the binned counts as "intensities" (rows x
+/// columns x spectrum bins, binned as set by `image.config.spectrum`), the stage positions of the
+/// pixel centers as "x" and "y" (mm), the bin centers as "mz" (and "tof" (ps) for time binning),
+/// and the calibration and binning in the attributes of the group, all in a Zarr store (e.g.,
+/// "two_grids.zarr"); only the bands of rows the stage is still passing are held in memory
+pub struct Datacube {
+    cube: ChunkedCube,
+    path: PathBuf,
+    config: image::Config,
+    tof_max: i64,
+}
+
+impl Datacube {
+    /// creates the store at `path` with everything but the counts
+    pub fn create(image: &image::Image, path: &Path, config: &DatacubeConfig) -> Result<Datacube, Box<dyn Error>> {
+        let cfg = image.config;
+        let (rows, cols) = (cfg.rows() as usize, cfg.cols() as usize);
+        let tof_max = cfg.spectrum.tof_max(Some(cfg.tof_pulse_length));
+        let bins = cfg.spectrum.bins(tof_max, &cfg.calibration);
+
+        std::fs::create_dir_all(path)?;
+        std::fs::write(path.join(".zgroup"), serde_json::to_string_pretty(&json!({"zarr_format": 2}))?)?;
+        let attributes = json!({
+            "source": image.tpx3_path,
+            "calibration": cfg.calibration,
+            "spectrum": cfg.spectrum,
+            "pixels_per_mm": cfg.pixels_per_mm,
+            "tof_pulse_length": cfg.tof_pulse_length,
+        });
+        std::fs::write(path.join(".zattrs"), serde_json::to_string_pretty(&attributes)?)?;
+        let center = |i: usize| (i as f64 + 0.5) / cfg.pixels_per_mm;
+        write_axis(&path.join("x"), &(0..cols).map(center).collect::<Vec<f64>>(), "x", "mm")?;
+        write_axis(&path.join("y"), &(0..rows).map(center).collect::<Vec<f64>>(), "y", "mm")?;
+        let mut axis = mass::Spectrum::new(&cfg.spectrum);
+        axis.counts = vec![0; bins];
+        let centers: Vec<f64> = axis.axis().iter().map(|edge| edge + axis.width() / 2.0).collect();
+        match cfg.spectrum.binning {
+            mass::Binning::Time { .. } => {
+                let mzs: Vec<f64> = centers.iter().map(|&t| cfg.calibration.time_to_mass(t as i64)).collect();
+                write_axis(&path.join("mz"), &mzs, "bin", "m/z")?;
+                write_axis(&path.join("tof"), &centers, "bin", "ps")?;
+            }
+            mass::Binning::Mass { .. } => write_axis(&path.join("mz"), &centers, "bin", "m/z")?,
+        }
+        let cube = ChunkedCube::create(&path.join("intensities"), [rows, cols, bins], *config)?;
+        Ok(Datacube { cube, path: path.to_path_buf(), config: cfg, tof_max })
+    }
+
+    /// counts a (counted, see `Hit::is_counted`) hit of a pulse at its pixel and bin
+    pub fn add(&mut self, hit: &Hit, pulse_time: i64, coordinates: &Coord) {
+        let cfg = &self.config;
+        let (col, row) = hit.rasterize(cfg, coordinates);
+        let tof = (hit.toa - pulse_time) % cfg.tof_pulse_length;
+        if let Some(bin) = cfg.spectrum.index(tof, self.tof_max, &cfg.calibration) {
+            self.cube.add(row, col, bin);
+        }
+    }
+
+    /// called when the stage turns
+    pub fn end_pass(&mut self) -> Result<(), Box<dyn Error>> { self.cube.end_pass() }
+
+    /// fills the cube in a pass of its own over the events of the image and finishes it; used when
+    /// there is no imzML export to fill it in the same pass
+    pub fn fill(mut self, image: &image::Image) -> Result<(), Box<dyn Error>> {
+        let coords = image.meta.coordinates.as_ref().ok_or("Coordinates not present!")?;
+        let dead_pix = image.meta.dead_pixels.as_ref().ok_or("Dead pixels not present!")?;
+        let mut direction = Direction::Right; // to determine if there is a new pass
+        for (pulse, coordinates) in TPX3Reader::new(&image.tpx3_path)?.zip(coords).filter(|(_, c)| c.is_not_inf()) {
+            for hit in pulse.hits.iter().filter(|h| h.is_counted(dead_pix)) {
+                self.add(hit, pulse.time, coordinates);
+            }
+            if coordinates.direction != direction {
+                direction = coordinates.direction;
+                self.end_pass()?;
+            }
+        }
+        self.finish()
+    }
+
+    /// writes the remaining counts
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.cube.finish()?;
+        let [rows, cols, bins] = self.cube.shape;
+        println!(
+            "datacube {}x{}x{}: {} chunk writes, {} of {} chunks empty, at most {} counts held in memory",
+            rows,
+            cols,
+            bins,
+            self.cube.chunks_written,
+            self.cube.chunk_count() - count_chunk_files(&self.path.join("intensities"))?,
+            self.cube.chunk_count(),
+            self.cube.peak_counts_held
+        );
+        Ok(())
+    }
+}
+
+/// writes the datacube of an image (the same pixels as `IMZMLMaker::stream_convert_and_save`) to
+/// a Zarr store at `path` in a pass of its own
+pub fn save_datacube(image: &image::Image, path: &Path, config: &DatacubeConfig) -> Result<(), Box<dyn Error>> {
+    Datacube::create(image, path, config)?.fill(image)
+}
+
+fn count_chunk_files(dir: &Path) -> Result<usize, Box<dyn Error>> {
+    let entries = std::fs::read_dir(dir)?.filter_map(Result::ok);
+    Ok(entries.filter(|e| !e.file_name().to_string_lossy().starts_with('.')).count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cubes_are_written_as_sparse_zlib_chunks() {
+        let dir = std::env::temp_dir().join(format!("two_grids_cube_{}", std::process::id()));
+        let config = DatacubeConfig { chunk_rows: 2, chunk_cols: 4, chunk_bins: 4, level: 6 };
+        let mut cube = ChunkedCube::create(&dir, [5, 6, 10], config).unwrap();
+        cube.add(0, 1, 2);
+        cube.add(0, 1, 2);
+        cube.add(4, 5, 9);
+        cube.add(5, 0, 0); // outside
+        for _ in 0..3 {
+            cube.end_pass().unwrap();
+            cube.add(4, 5, 9); // keeps the last band open
+        }
+        assert_eq!(cube.chunks_written, 0);
+        cube.end_pass().unwrap(); // the first band has been idle for three passes
+        assert_eq!(cube.chunks_written, 1);
+        cube.add(1, 1, 2); // the stage came back
+        cube.finish().unwrap();
+        assert_eq!(cube.chunk_count(), 3 * 2 * 3);
+        assert_eq!(count_chunk_files(&dir).unwrap(), 2);
+
+        let chunk = read_chunk(&dir, [0, 0, 0], [2, 4, 4]).unwrap().unwrap();
+        assert_eq!((chunk[6], chunk[22]), (2, 1)); // (row, col, bin) (0, 1, 2) and (1, 1, 2)
+        assert_eq!(chunk.iter().sum::<u32>(), 3);
+        let chunk = read_chunk(&dir, [2, 1, 2], [2, 4, 4]).unwrap().unwrap();
+        assert_eq!((chunk[5], chunk.iter().sum::<u32>()), (4, 4)); // (0, 1, 1) in the chunk
+        assert_eq!(read_chunk(&dir, [1, 0, 0], [2, 4, 4]).unwrap(), None);
+        let zarray: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.join(".zarray")).unwrap()).unwrap();
+        assert_eq!((zarray["shape"].to_string(), zarray["chunks"].to_string()), ("[5,6,10]".into(), "[2,4,4]".into()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
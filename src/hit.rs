@@ -2,6 +2,12 @@
 
 use crate::{image, reader::HIT_LIMIT, stage::Coord};
 
+/// header byte of the blob packets written by version 1 (summed ToT not recoverable)
+pub const BLOB_HEADER_V1: u8 = 0xCA;
+/// header byte of the current (version 2) blob packets
+pub const BLOB_HEADER: u8 = 0xCB;
+const BLOB_TOT_MAX: u64 = 0x00FF_FFFF; // 24 bit summed ToT field, in units of 25 ns
+
 /// a structure for holding "hit" data
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Hit {
@@ -34,27 +40,38 @@ impl Hit {
         let pix = ((col % 2) << 2) | (row % 4); // extract pix from row/col
         let col_bits: u64 = (col - (pix / 4)) << 52; // use pix to find col bits
         let row_bits: u64 = (row - (pix & 0x3)) << 45; // use pix to find row bits
-        let tot_bits: u64 = ((tot / 25) % 1024) << 20; // 1024 is for large, clustered TOTs
-        let global_time = toa / 409_600_000; // extract "coarse" toa from the global time
+        let tot_bits: u64 = (tot / 25).min(0x3FF) << 20; // clustered ToT sums are in the blob packet
+        let mut global_time = toa / 409_600_000; // extract "coarse" toa from the global time
         let remainder = (toa % 409_600_000) / (25_000 / 16);
-        let remainder = remainder - (remainder / 3125); // fix off-by-0.5 error (3125 = 1562.5 * 2)
+        let mut remainder = remainder - (remainder / 3125); // fix off-by-0.5 error (3125 = 1562.5 * 2)
+        if remainder >> 18 != 0 { // the last ~1.4 ns of a coarse period rounds into the next one
+            global_time = (global_time + 1) & 0xFFFF;
+            remainder = 0;
+        }
         let fta_bit = (!remainder & 0xF) << 16; // extract fine bits
         let cta_bit = ((remainder & !0xF) >> 4) << 30; // extract course bits
         header | col_bits | row_bits | (pix << 44) | cta_bit | tot_bits | fta_bit | global_time
     }
 
+    /// packs the cluster information into a "blob" packet that directly follows its hit packet;
+    /// the header byte carries the format version and the fields are (in bits, from the top):
+    /// header (8) | col offset (8) | row offset (8) | summed ToT in 25 ns (24) | cluster size (16)
     pub fn to_blob_packet(self) -> u64 {
-        let header = 0xCAu64 << 56; // 1 byte
-        let col_offset_bits = (self.col_offset as u64) << 48; // 1 byte
-        let row_offset_bits = (self.row_offset as u64) << 40; // 1 byte
-        let tot_coarse = (self.tot as u64 / 1024) & 0x00FF_FFFF << 16; // 3 bytes
-        let size = self.size as u64; // 2 bytes
-        header | col_offset_bits | row_offset_bits | tot_coarse | size // 1| 1 | 1 | 3 | 2
+        let header = (BLOB_HEADER as u64) << 56;
+        let col_offset_bits = (self.col_offset as u64) << 48;
+        let row_offset_bits = (self.row_offset as u64) << 40;
+        let tot_bits = (self.tot as u64 / 25).min(BLOB_TOT_MAX) << 16; // saturates at ~419 ms
+        let size = self.size as u64;
+        header | col_offset_bits | row_offset_bits | tot_bits | size
     }
 
+    /// reads both blob packet versions; version 1 ("0xCA") masked its coarse ToT away when it
+    /// was written, so for those only the (10 bit) ToT of the hit packet is kept
     pub fn update_with_blob_packet(&mut self, packet: u64) {
         unsafe {
-            self.tot += (packet.unchecked_shr(16) & 0x00FF_FFFF).unchecked_mul(1024 * 25) as u32;
+            if packet.unchecked_shr(56) as u8 == BLOB_HEADER {
+                self.tot = (packet.unchecked_shr(16) & BLOB_TOT_MAX).unchecked_mul(25) as u32;
+            }
             self.col_offset = (packet.unchecked_shr(48) & 0xFF) as u8;
             self.row_offset = (packet.unchecked_shr(40) & 0xFF) as u8;
             self.size = (packet & 0xFFFF) as u16;
@@ -101,7 +118,7 @@ impl Hit {
         new
     }
 
-    pub fn to_cr(&self) -> u16 { (((self.col as u16) << 8)) | (self.row as u16) }
+    pub fn to_cr(self) -> u16 { ((self.col as u16) << 8) | (self.row as u16) }
 
     pub fn is_dead(&self, dead_pixels: &[u16]) -> bool {
         let cr = &self.to_cr();
@@ -111,6 +128,10 @@ impl Hit {
         // dead_pixels.iter().any(|&dp| dp == self.to_cr())
         
     
+    /// whether the hit counts in the images and exports: single hits on dead pixels are noise,
+    /// clusters that touch one are still ions
+    pub fn is_counted(&self, dead_pixels: &[u16]) -> bool { self.size > 1 || !self.is_dead(dead_pixels) }
+
     pub fn rasterize(&self, cfg: &image::Config, c: &Coord) -> (usize, usize) {
         let center = 127.5;
         let fcol = self.col as f64 + (self.col_offset as f64 / 255.0) - center;
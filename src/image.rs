@@ -1,12 +1,17 @@
 use std::error::Error;
 
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    mass, reader,
-    stage::{Coord, Direction},
+    mass, reader, roi,
+    stage::{self, Coord, Direction},
     // hit::Hit,
 };
 
-#[derive(Copy, Clone)]
+/// memoized fields are not serialized; call `update` after deserializing
+#[derive(Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     /// TOF_PULSE_LENGTH: i64 = 94_554_700; // for 1000 m/z
     /// TOF_PULSE_LENGTH: i64 = 56_687_500; // for 350 m/z
@@ -16,17 +21,25 @@ pub struct Config {
     pub width: f64, // in mm
     pub height: f64,     // in mm
     pub rotation: f64,   // angle of rotation 2.715,2.775,2.82
+    #[serde(skip)]
     pub rot_sin: f64,    // memoized
+    #[serde(skip)]
     pub rot_cos: f64,    // memoized
     pub camera_fov: f64, // fov of pixels 330.0 / 255.0
     pub pixels_per_mm: f64,
     pub scale_x: f64,
     pub scale_y: f64,
+    #[serde(skip)]
     pub scale_x_fov: f64,      // memoized
+    #[serde(skip)]
     pub scale_y_fov: f64,      // memoized
     pub tof_pulse_length: i64, // in ps
-    pub peak_time_window: i64, // in ps, time window for mass selection
+    pub peak_time_window: i64, // in ps, time window for mass selection (start inclusive, end exclusive)
     pub peak_time: Option<i64>,
+    pub calibration: mass::MassCalibration, // time-of-flight to m/z
+    pub peak_picking: mass::PeakPicking,    // for the automatic mass list
+    pub spectrum: mass::SpectrumConfig,     // binning and range of the spectrum reports
+    pub scaling: Scaling,                   // of the saved images
 }
 
 impl Default for Config {
@@ -44,8 +57,12 @@ impl Default for Config {
             scale_x_fov: 1.0 * 0.001 * 345.0 / 256.0,
             scale_y_fov: 1.0 * 0.001 * 345.0 / 256.0,
             tof_pulse_length: 0,       // i64 in ps
-            peak_time_window: 100_000, // +/- 100 ns
+            peak_time_window: 150_000, // +/- 150 ns
             peak_time: None,
+            calibration: mass::MassCalibration::default(),
+            peak_picking: mass::PeakPicking::default(),
+            spectrum: mass::SpectrumConfig::default(),
+            scaling: Scaling::Linear,
         }
     }
 }
@@ -76,7 +93,7 @@ impl Config {
 pub struct Metadata {
     pub dead_pixels: Option<Vec<u16>>, // col, row out of 256 for dead pixels
     pub coordinates: Option<Vec<Coord>>,  // x, y, direction of stage_motion travel
-    pub found_peaks: Option<Vec<i64>>,    // in ps, list of peak times -> each gens 1 image
+    pub found_peaks: Option<Vec<mass::Peak>>, // each gens 1 image
 }
 
 /// This is simply a helper struct to combine the "coordinates" that can be generated from the
@@ -134,6 +151,14 @@ impl Image {
         Ok(())
     }
 
+    /// the coordinates of every pulse from a position log of the stage instead of from the timing
+    /// of the pulses
+    pub fn coordinates_from_stage_log(&mut self, stage_log: &stage::StageLog) -> Result<(), Box<dyn Error>> {
+        let tdcs: Vec<i64> = reader::TDCReader::new(&self.tpx3_path)?.collect();
+        self.meta.coordinates = Some(stage_log.coordinates(&tdcs)?);
+        Ok(())
+    }
+
     /// finds any overactive / dead pixels and provides their coordinates to allow for masking
     pub fn auto_generate_dead_pixels(&mut self) -> Result<(), Box<dyn Error>> {
         if self.meta.dead_pixels.is_none() {
@@ -150,10 +175,23 @@ impl Image {
         Ok(())
     }
 
-    /// simple function to integrate and then peak pick overall mass spectrum
-    pub fn auto_generate_mass_list(&mut self) -> Result<Option<Vec<i64>>, Box<dyn Error>> {
-        let (times, ints) = mass::spectrum(&self.tpx3_path, Some(self.config.tof_pulse_length))?;
-        self.meta.found_peaks = Some(mass::find_peaks(&ints).iter().map(|&p| times[p]).collect());
+    /// simple function to integrate and then peak pick overall mass spectrum; peaks are picked in
+    /// time, so m/z binning falls back to the default time bins, and without the single hits on
+    /// dead pixels, so a hot pixel does not become a peak
+    pub fn auto_generate_mass_list(&mut self) -> Result<Option<Vec<mass::Peak>>, Box<dyn Error>> {
+        let dead_pix = self.meta.dead_pixels.as_ref().ok_or("Dead pixels not generated")?;
+        let mut spectrum_config = self.config.spectrum;
+        if let mass::Binning::Mass { .. } = spectrum_config.binning {
+            spectrum_config.binning = mass::SpectrumConfig::default().binning;
+        }
+        let spectrum = mass::spectrum(
+            &self.tpx3_path,
+            Some(self.config.tof_pulse_length),
+            &spectrum_config,
+            &self.config.calibration,
+            dead_pix,
+        )?;
+        self.meta.found_peaks = Some(mass::find_peaks(&spectrum, &self.config.peak_picking));
         println!("{} peaks found!", self.meta.found_peaks.as_ref().ok_or("No peaks found!")?.len());
         Ok(self.meta.found_peaks.clone())
     }
@@ -184,7 +222,11 @@ impl Image {
 
     /// to make a buffer suitable for saving directly as a .png -> useful for tic images or
     /// pairing/modifying for individual mass images
-    pub fn to_buffer(&self) -> Result<Vec<u16>, Box<dyn Error>> {
+    pub fn to_buffer<T: Counts>(&self) -> Result<Vec<T>, Box<dyn Error>> { self.to_buffer_where(|_| true) }
+
+    /// `to_buffer` of only the hits with a time of flight (ps) that is kept, e.g., those an imzML
+    /// file holds
+    pub fn to_buffer_where<T: Counts>(&self, keep: impl Fn(i64) -> bool) -> Result<Vec<T>, Box<dyn Error>> {
         let reader = reader::TPX3Reader::new(&self.tpx3_path)?;
         let coords = self.meta.coordinates.as_ref().ok_or("Coordinates not generated")?;
         let dead_pix = self.meta.dead_pixels.as_ref().unwrap();
@@ -193,10 +235,10 @@ impl Image {
         let (sin, cos) = (self.config.rot_sin, self.config.rot_cos);
         let (rows, cols) = (self.config.rows() as usize, self.config.cols() as usize);
         let (xfov, yfov) = (self.config.scale_x_fov, self.config.scale_y_fov);
-        let mut buffer = vec![0; cols * rows];
+        let mut buffer = vec![T::default(); cols * rows];
         for (pulse, coordinates) in reader.zip(coords).filter(|(_, c)| c.is_not_inf()) {
             let (cx, cy, time) = (coordinates.x, coordinates.y, pulse.time);
-            for hit in pulse.hits.iter().filter(|h| h.size > 1 || !h.is_dead(&dead_pix)) {
+            for hit in pulse.hits.iter().filter(|h| h.is_counted(dead_pix) && keep((h.toa - time) % tpl)) {
                 let (xrot, yrot) = hit.rotate(sin, cos);
                 let icol = indexify(xfov, ppmm, xrot, cx);
                 let irow = indexify(yfov, ppmm, yrot, cy);
@@ -206,26 +248,92 @@ impl Image {
             }
         }
         println!("Made buffer!");
+        report_saturation(&buffer);
         Ok(buffer)
     }
 
+    /// the spectra of the hits landing in each region of interest (in the pixels of `to_buffer`),
+    /// all accumulated in one (parallel) pass over the data
+    pub fn roi_spectra(&self, rois: &[roi::Roi]) -> Result<Vec<mass::Spectrum>, Box<dyn Error>> {
+        let reader = reader::TPX3Reader::new(&self.tpx3_path)?;
+        let coords = self.meta.coordinates.as_ref().ok_or("Coordinates not generated")?;
+        let dead_pix = self.meta.dead_pixels.as_ref().ok_or("Dead pixels not generated")?;
+        let cfg = &self.config;
+        let ppmm = cfg.pixels_per_mm;
+        let (sin, cos) = (cfg.rot_sin, cfg.rot_cos);
+        let (rows, cols) = (cfg.rows() as usize, cfg.cols() as usize);
+        let (xfov, yfov) = (cfg.scale_x_fov, cfg.scale_y_fov);
+        let membership = roi::rasterize_all(rois, cols, rows)?;
+        let tof_max = cfg.spectrum.tof_max(Some(cfg.tof_pulse_length));
+        let empty = || vec![mass::Spectrum::new(&cfg.spectrum); rois.len()];
+        let spectra = reader
+            .zip(coords)
+            .filter(|(_, c)| c.is_not_inf())
+            .par_bridge()
+            .fold(empty, |mut spectra, (pulse, coordinates)| {
+                let (cx, cy, time) = (coordinates.x, coordinates.y, pulse.time);
+                for hit in pulse.hits.iter().filter(|h| h.is_counted(dead_pix)) {
+                    let (xrot, yrot) = hit.rotate(sin, cos);
+                    let icol = indexify(xfov, ppmm, xrot, cx);
+                    let irow = indexify(yfov, ppmm, yrot, cy);
+                    if irow >= rows || icol >= cols || membership[icol + irow * cols] == 0 {
+                        continue;
+                    }
+                    let tof = (hit.toa - time) % cfg.tof_pulse_length;
+                    if let Some(index) = cfg.spectrum.index(tof, tof_max, &cfg.calibration) {
+                        let bits = membership[icol + irow * cols];
+                        for (i, spectrum) in spectra.iter_mut().enumerate().filter(|(i, _)| bits >> i & 1 == 1) {
+                            spectrum.increment(index);
+                        }
+                    }
+                }
+                spectra
+            })
+            .reduce(empty, |a, b| a.into_iter().zip(b).map(|(a, b)| a.merge(b)).collect());
+        Ok(spectra)
+    }
+
     /// to make a buffer suitable for saving directly as a .png -> useful for tic images or
     /// pairing/modifying for individual mass images
-    pub fn times_to_buffers(&self, pts: &[i64]) -> Result<Vec<u16>, Box<dyn Error>> {
+    pub fn times_to_buffers<T: Counts>(&self, pts: &[i64]) -> Result<Vec<T>, Box<dyn Error>> {
+        let ptw = self.config.peak_time_window;
+        let windows: Vec<(i64, i64)> = pts.iter().map(|&pt| (pt - ptw, pt + ptw)).collect();
+        self.windows_to_buffers(&windows)
+    }
+
+    /// one image per time of flight window (ps, start inclusive and end exclusive), all made in one
+    /// pass over the data; the images follow each other in the returned buffer
+    pub fn windows_to_buffers<T: Counts>(&self, windows: &[(i64, i64)]) -> Result<Vec<T>, Box<dyn Error>> {
+        self.windows_to_buffers_where(windows, |_| true)
+    }
+
+    /// `windows_to_buffers` of only the hits with a time of flight (ps) that is kept
+    pub fn windows_to_buffers_where<T: Counts>(
+        &self, windows: &[(i64, i64)], keep: impl Fn(i64) -> bool,
+    ) -> Result<Vec<T>, Box<dyn Error>> {
         let reader = reader::TPX3Reader::new(&self.tpx3_path)?;
         let coords = self.meta.coordinates.as_ref().expect("coordinates not generated!");
         let (dead_pix, cfg) = (self.meta.dead_pixels.as_ref().unwrap(), self.config);
         let ppmm = cfg.pixels_per_mm;
-        let (tpl, ptw) = (cfg.tof_pulse_length as i32, cfg.peak_time_window as u64);
+        let tpl = cfg.tof_pulse_length;
         let (sin, cos) = (cfg.rot_sin, cfg.rot_cos);
         let (rows, cols) = (cfg.rows() as usize, cfg.cols() as usize);
         let (xfov, yfov) = (cfg.scale_x_fov, cfg.scale_y_fov);
-        let mut buffers = vec![0; cols * rows * pts.len()];
+        // sorted by start, the windows a time falls in start within the widest window before it
+        let mut order: Vec<usize> = (0..windows.len()).collect();
+        order.sort_by_key(|&j| windows[j].0);
+        let starts: Vec<i64> = order.iter().map(|&j| windows[j].0).collect();
+        let widest = windows.iter().map(|(start, end)| end - start).max().unwrap_or(0);
+        let mut buffers = vec![T::default(); cols * rows * windows.len()];
         for (pulse, coordinates) in reader.zip(coords) {
             let (cx, cy, time) = (coordinates.x, coordinates.y, pulse.time);
-            for hit in pulse.hits.iter().filter(|h| h.size > 1 || !h.is_dead(&dead_pix)) {
-                let t = ((hit.toa - time) as i32 % tpl) as u64; // i32 shaves off time
-                for (j, _) in pts.iter().enumerate().filter(|(_, &pt)| betwix(t, pt as u64, ptw)) {
+            for hit in pulse.hits.iter().filter(|h| h.is_counted(dead_pix)) {
+                let t = (hit.toa - time) % tpl;
+                if !keep(t) {
+                    continue;
+                }
+                let candidates = starts.partition_point(|&s| s <= t - widest)..starts.partition_point(|&s| s <= t);
+                for &j in order[candidates].iter().filter(|&&j| t < windows[j].1) {
                     let (xrot, yrot) = hit.rotate(sin, cos);
                     let icol = indexify(xfov, ppmm, xrot, cx);
                     let irow = indexify(yfov, ppmm, yrot, cy);
@@ -236,16 +344,123 @@ impl Image {
             }
         }
         println!("Made buffers!");
+        report_saturation(&buffers);
         Ok(buffers)
     }
 }
 
-fn increment(buffers: &mut Vec<u16>, icol: usize, irow: usize, cols: usize, cr: usize, i: usize) {
-    unsafe { *buffers.get_unchecked_mut(make_index(icol, irow, cols, cr, i)) += 1; }
+/// pixel values images can be accumulated in; counting stops (saturates) at `SATURATION`, which
+/// for f32 is where adding one is lost to rounding
+pub trait Counts: Copy + Default + PartialOrd + Send + Sync {
+    const SATURATION: Self;
+
+    fn increment(&mut self);
+
+    fn to_f64(self) -> f64;
+}
+
+impl Counts for u16 {
+    const SATURATION: u16 = u16::MAX;
+
+    fn increment(&mut self) { *self = self.saturating_add(1) }
+
+    fn to_f64(self) -> f64 { self as f64 }
 }
 
-fn increment_total(buffers: &mut Vec<u16>, icol: usize, irow: usize, cols: usize) {
-    unsafe { *buffers.get_unchecked_mut(icol.unchecked_add(irow.unchecked_mul(cols))) += 1; }
+impl Counts for u32 {
+    const SATURATION: u32 = u32::MAX;
+
+    fn increment(&mut self) { *self = self.saturating_add(1) }
+
+    fn to_f64(self) -> f64 { self as f64 }
+}
+
+impl Counts for f32 {
+    const SATURATION: f32 = 16_777_216.0; // 2^24
+
+    fn increment(&mut self) { *self += 1.0 }
+
+    fn to_f64(self) -> f64 { self as f64 }
+}
+
+/// the number of saturated pixels, whose counts are clipped
+pub fn saturated<T: Counts>(buffer: &[T]) -> usize { buffer.iter().filter(|&&v| v >= T::SATURATION).count() }
+
+fn report_saturation<T: Counts>(buffer: &[T]) {
+    let clipped = saturated(buffer);
+    if clipped > 0 {
+        println!("{} pixels saturated at {} counts, use a wider accumulator", clipped, T::SATURATION.to_f64());
+    }
+}
+
+/// the png value of the top of the scaling range
+pub const FULL_SCALE: f64 = 65530.0;
+
+/// how counts are mapped to the 16 bit values of saved images
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Scaling {
+    Linear,                              // from zero to the image maximum
+    Percentile { low: f64, high: f64 },  // linear between percentiles (0-100) of the non-zero pixels
+    Log,                                 // log(1 + counts), from zero to the image maximum
+    Sqrt,                                // square root, from zero to the image maximum
+    Fixed { min: f64, max: f64 },        // linear between absolute counts
+    Shared,                              // linear from zero to the maximum of a set of images
+    Raw,                                 // the counts themselves, clipped at 65535
+}
+
+impl Scaling {
+    /// the counts mapped to zero and to full scale
+    pub fn range<T: Counts>(&self, buffer: &[T]) -> (f64, f64) {
+        let max = || buffer.iter().map(|v| v.to_f64()).fold(0.0, f64::max);
+        match *self {
+            Scaling::Linear | Scaling::Log | Scaling::Sqrt | Scaling::Shared => (0.0, max()),
+            Scaling::Percentile { low, high } => {
+                let mut values: Vec<f64> = buffer.iter().map(|v| v.to_f64()).filter(|&v| v > 0.0).collect();
+                values.sort_by(|a, b| a.total_cmp(b));
+                let percentile = |p: f64| match values.len() {
+                    0 => 0.0,
+                    n => values[((p / 100.0 * (n - 1) as f64).round() as usize).min(n - 1)],
+                };
+                (percentile(low), percentile(high))
+            }
+            Scaling::Fixed { min, max } => (min, max),
+            Scaling::Raw => (0.0, u16::MAX as f64),
+        }
+    }
+
+    /// `Shared` becomes the fixed range from zero to the maximum of all the buffers, so that the
+    /// images of a set are comparable; other scalings are returned as they are
+    pub fn resolve<'a, T: Counts + 'a>(&self, buffers: impl IntoIterator<Item = &'a [T]>) -> Scaling {
+        match self {
+            Scaling::Shared => {
+                let max = buffers.into_iter().flatten().map(|v| v.to_f64()).fold(0.0, f64::max);
+                Scaling::Fixed { min: 0.0, max }
+            }
+            other => *other,
+        }
+    }
+
+    /// the 16 bit value of a count given the range of the image
+    pub fn apply(&self, value: f64, (low, high): (f64, f64)) -> u16 {
+        let fraction = match self {
+            Scaling::Raw => return value.clamp(0.0, u16::MAX as f64) as u16,
+            Scaling::Log => (1.0 + value.max(0.0)).ln() / (1.0 + high).ln(),
+            Scaling::Sqrt => value.max(0.0).sqrt() / high.sqrt(),
+            _ => (value - low) / (high - low),
+        };
+        match fraction.is_nan() {
+            true => 0, // empty images
+            false => (fraction.clamp(0.0, 1.0) * FULL_SCALE) as u16,
+        }
+    }
+}
+
+fn increment<T: Counts>(buffers: &mut [T], icol: usize, irow: usize, cols: usize, cr: usize, i: usize) {
+    unsafe { buffers.get_unchecked_mut(make_index(icol, irow, cols, cr, i)).increment() }
+}
+
+fn increment_total<T: Counts>(buffers: &mut [T], icol: usize, irow: usize, cols: usize) {
+    unsafe { buffers.get_unchecked_mut(icol.unchecked_add(irow.unchecked_mul(cols))).increment() }
 }
 
 fn indexify(fov: f64, ppmm: f64, rot: f64, coord: f64) -> usize {
@@ -262,4 +477,47 @@ pub fn betwix(v: u64, pt: u64, ptw: u64) -> bool {
 
 pub fn is_between(value: i64, high: i64, low: i64) -> bool { value < high && value > low }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count<T: Counts>(times: usize) -> T {
+        let mut value = T::default();
+        (0..times).for_each(|_| value.increment());
+        value
+    }
+
+    #[test]
+    fn counts_saturate_instead_of_wrapping() {
+        assert_eq!(count::<u16>(70_000), u16::MAX);
+        assert_eq!(count::<u32>(70_000), 70_000);
+        assert_eq!(count::<f32>(70_000), 70_000.0);
+        let mut wide = 16_777_215.0f32;
+        (0..3).for_each(|_| wide.increment());
+        assert_eq!(wide, f32::SATURATION);
+        assert_eq!(saturated(&[count::<u16>(70_000), count(3), u16::MAX - 1]), 1);
+        assert_eq!(saturated(&[wide, 1.0]), 1);
+    }
 
+    #[test]
+    fn scalings_map_counts_to_16_bit_values() {
+        let buffer: Vec<u32> = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 1000];
+        let full = FULL_SCALE as u16;
+        let scaled = |scaling: Scaling, value: f64| scaling.apply(value, scaling.range(&buffer));
+        assert_eq!((scaled(Scaling::Linear, 500.0), scaled(Scaling::Linear, 1000.0)), (full / 2, full));
+        let percentile = Scaling::Percentile { low: 0.0, high: 90.0 };
+        assert_eq!(percentile.range(&buffer), (1.0, 10.0)); // zeros are left out
+        assert_eq!((scaled(percentile, 1.0), scaled(percentile, 1000.0)), (0, full));
+        assert_eq!(scaled(Scaling::Log, 0.0), 0);
+        assert_eq!(scaled(Scaling::Log, 1000.0), full);
+        assert!(scaled(Scaling::Log, 10.0) > scaled(Scaling::Sqrt, 10.0));
+        assert!(scaled(Scaling::Sqrt, 10.0) > scaled(Scaling::Linear, 10.0));
+        assert_eq!(scaled(Scaling::Fixed { min: 10.0, max: 20.0 }, 15.0), full / 2);
+        assert_eq!((scaled(Scaling::Raw, 1000.0), scaled(Scaling::Raw, 1e6)), (1000, u16::MAX));
+        let other: Vec<u32> = vec![0, 4000];
+        let shared = Scaling::Shared.resolve([&buffer[..], &other[..]]);
+        assert_eq!(shared, Scaling::Fixed { min: 0.0, max: 4000.0 });
+        assert_eq!(Scaling::Log.resolve([&other[..]]), Scaling::Log);
+        assert_eq!(Scaling::Linear.apply(0.0, Scaling::Linear.range(&[0u16, 0])), 0);
+    }
+}
@@ -1,19 +1,21 @@
 use std::{
-    collections::HashMap,
-    convert::TryInto,
     error::Error,
     io::{Read, Seek, SeekFrom, Write},
     num::ParseIntError,
+    ops::Range,
 };
 
+use flate2::{write::ZlibEncoder, Compression};
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use simple_uuid::v4;
 
 use crate::{
-    image, mass,
-    pixel::{Pixel, PixelSpan},
+    datacube, image, mass,
+    pixel::{Pixel, PixelSpan, RowStore},
     reader::TPX3Reader,
     stage::Direction,
+    writer::escape_xml,
 };
 
 
@@ -22,36 +24,352 @@ const IMZML_FOOTER: &str = r#"
     </run>
 </mzML>"#;
 
+/// processed spectra hold an m/z array per pixel (of the m/z values of its events below m/z 300),
+/// continuous spectra the counts in the bins of `image::Config::spectrum` with one m/z array shared
+/// by all; centroid spectra hold the m/z of the found peaks the events of a pixel are assigned to
+/// (those within `image::Config::peak_time_window` of the apex) and their counts
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    Processed,
+    Continuous,
+    Centroid,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Mode, String> {
+        match mode.to_lowercase().as_str() {
+            "processed" => Ok(Mode::Processed),
+            "continuous" => Ok(Mode::Continuous),
+            "centroid" => Ok(Mode::Centroid),
+            _ => Err(format!("unknown imzML mode {:?}, use processed, continuous or centroid", mode)),
+        }
+    }
+}
+
+/// the type of the values of the m/z arrays
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MzType {
+    F32,
+    F64,
+}
+
+impl MzType {
+    /// the name and accession of the type
+    fn cv(self) -> (&'static str, &'static str) {
+        match self {
+            MzType::F32 => ("32-bit float", "MS:1000521"),
+            MzType::F64 => ("64-bit float", "MS:1000523"),
+        }
+    }
+
+    fn to_bytes(self, mzs: &[f64]) -> Vec<u8> {
+        match self {
+            MzType::F32 => mzs.iter().flat_map(|&m| (m as f32).to_le_bytes()).collect(),
+            MzType::F64 => mzs.iter().flat_map(|m| m.to_le_bytes()).collect(),
+        }
+    }
+
+    /// the type with the accession, if it is one of ours
+    pub(crate) fn from_accession(accession: &str) -> Option<MzType> {
+        [MzType::F32, MzType::F64].iter().copied().find(|t| t.cv().1 == accession)
+    }
+
+    pub(crate) fn decode(self, bytes: &[u8]) -> Vec<f64> {
+        match self {
+            MzType::F32 => bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64).collect(),
+            MzType::F64 => bytes
+                .chunks_exact(8)
+                .map(|b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+                .collect(),
+        }
+    }
+}
+
+/// the type of the values of the intensity arrays; integer counts saturate at the type maximum
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IntensityType {
+    I16,
+    I32,
+    F32,
+}
+
+impl IntensityType {
+    fn cv(self) -> (&'static str, &'static str) {
+        match self {
+            IntensityType::I16 => ("16-bit integer", "IMS:1100001"),
+            IntensityType::I32 => ("32-bit integer", "MS:1000519"),
+            IntensityType::F32 => ("32-bit float", "MS:1000521"),
+        }
+    }
+
+    /// the count as it is stored, saturated at the maximum of integer types
+    fn saturate(self, count: u32) -> u32 {
+        match self {
+            IntensityType::I16 => count.min(i16::MAX as u32),
+            IntensityType::I32 => count.min(i32::MAX as u32),
+            IntensityType::F32 => count,
+        }
+    }
+
+    fn to_bytes(self, counts: &[u32]) -> Vec<u8> {
+        match self {
+            IntensityType::I16 => counts.iter().flat_map(|&c| (self.saturate(c) as i16).to_le_bytes()).collect(),
+            IntensityType::I32 => counts.iter().flat_map(|&c| (self.saturate(c) as i32).to_le_bytes()).collect(),
+            IntensityType::F32 => counts.iter().flat_map(|&c| (c as f32).to_le_bytes()).collect(),
+        }
+    }
+
+    /// the type with the accession, if it is one of ours
+    pub(crate) fn from_accession(accession: &str) -> Option<IntensityType> {
+        [IntensityType::I16, IntensityType::I32, IntensityType::F32].iter().copied().find(|t| t.cv().1 == accession)
+    }
+
+    pub(crate) fn decode(self, bytes: &[u8]) -> Vec<f64> {
+        match self {
+            IntensityType::I16 => bytes.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]]) as f64).collect(),
+            IntensityType::I32 => {
+                bytes.chunks_exact(4).map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64).collect()
+            }
+            IntensityType::F32 => {
+                bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64).collect()
+            }
+        }
+    }
+}
+
+/// a term of the PSI-MS (or imzML or unit) controlled vocabulary, e.g., "MS:1000084" for
+/// "time-of-flight"; without an accession it is written as a user parameter
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CvParam {
+    pub accession: String,
+    pub name: String,
+    #[serde(default)]
+    pub value: String,
+}
+
+impl CvParam {
+    pub fn new(accession: &str, name: &str, value: &str) -> CvParam {
+        CvParam { accession: accession.to_string(), name: name.to_string(), value: value.to_string() }
+    }
+
+    fn to_xml(&self) -> String {
+        let (name, value) = (escape_xml(&self.name), escape_xml(&self.value));
+        match self.accession.split(':').next() {
+            Some(cv) if !cv.is_empty() => {
+                format!(r#"<cvParam cvRef="{}" accession="{}" name="{}" value="{}"/>"#, cv, self.accession, name, value)
+            }
+            _ => format!(r#"<userParam name="{}" value="{}"/>"#, name, value),
+        }
+    }
+}
+
+/// the lines of a list of parameters at an indentation
+fn params_to_xml(params: &[CvParam], indent: usize) -> String {
+    params.iter().map(|p| format!("{}{}\n", " ".repeat(indent), p.to_xml())).collect()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Polarity {
+    Positive,
+    Negative,
+}
+
+/// the instrument and acquisition written in the imzML header; the defaults describe a
+/// laser desorption time-of-flight instrument with a Timepix3 camera; the run config should set the
+/// ionization actually used, e.g., matrix-assisted
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Instrument {
+    pub model: Vec<CvParam>, // e.g., the instrument model and serial number
+    pub source: Vec<CvParam>,
+    pub analyzer: Vec<CvParam>,
+    pub detector: Vec<CvParam>,
+    pub polarity: Polarity,
+    pub sample: String,      // written to the sample list if not empty
+    pub operator: String,    // the contact of the file if not empty
+    pub affiliation: String, // of the operator
+}
+
+impl Default for Instrument {
+    fn default() -> Instrument {
+        Instrument {
+            model: vec![
+                CvParam::new("MS:1000031", "instrument model", "fast mass microscope"),
+                CvParam::new("MS:1000529", "instrument serial number", "none"),
+            ],
+            source: vec![CvParam::new("MS:1000393", "laser desorption ionization", "")],
+            analyzer: vec![CvParam::new("MS:1000084", "time-of-flight", "")],
+            detector: vec![
+                CvParam::new("MS:1000114", "microchannel plate detector", ""),
+                CvParam::new("MS:1000119", "time-digital converter", ""),
+                CvParam::new("", "camera", "TPX3CAM (Timepix3)"),
+            ],
+            polarity: Polarity::Positive,
+            sample: String::new(),
+            operator: String::new(),
+            affiliation: String::new(),
+        }
+    }
+}
+
+/// the region of the image to export, in image pixels (col, row from the top left of the total
+/// ion count image) or in mm (x, y from the same corner); pixels partly inside are kept
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Crop {
+    Pixels { col: usize, row: usize, width: usize, height: usize },
+    Millimetres { x: f64, y: f64, width: f64, height: f64 },
+}
+
+impl Crop {
+    /// the columns and rows of a cols x rows image inside the crop, clipped to the image
+    pub fn ranges(&self, pixels_per_mm: f64, cols: usize, rows: usize) -> Result<(Range<usize>, Range<usize>), String> {
+        let (col, row, width, height) = match *self {
+            Crop::Pixels { col, row, width, height } => (col as f64, row as f64, width as f64, height as f64),
+            Crop::Millimetres { x, y, width, height } => {
+                (x * pixels_per_mm, y * pixels_per_mm, width * pixels_per_mm, height * pixels_per_mm)
+            }
+        };
+        let clip = |start: f64, len: f64, n: usize| {
+            (start.max(0.0).floor() as usize).min(n)..((start + len).max(0.0).ceil() as usize).min(n)
+        };
+        let (col_range, row_range) = (clip(col, width, cols), clip(row, height, rows));
+        if col_range.is_empty() || row_range.is_empty() {
+            return Err(format!("the crop {:?} does not overlap the {}x{} pixel image", self, cols, rows));
+        }
+        Ok((col_range, row_range))
+    }
+}
+
+/// how the imzML file is written
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImzmlConfig {
+    pub mode: Mode,
+    pub mz_type: MzType,
+    pub intensity_type: IntensityType,
+    pub zlib: bool,    // compresses each binary array
+    pub indexed: bool, // an indexedmzML file, with the offsets of the spectra and a checksum
+    pub instrument: Instrument,
+    pub crop: Option<Crop>,      // the whole image if not set
+    pub skip_empty: bool,        // pixels without events get no spectrum
+    pub memory_budget_mb: usize, // for the rows being accumulated, beyond it they are spilled to files
+}
+
+impl Default for ImzmlConfig {
+    fn default() -> ImzmlConfig {
+        ImzmlConfig {
+            mode: Mode::Processed,
+            mz_type: MzType::F32,
+            intensity_type: IntensityType::I16,
+            zlib: false,
+            indexed: true,
+            instrument: Instrument::default(),
+            crop: None,
+            skip_empty: false,
+            memory_budget_mb: 1024,
+        }
+    }
+}
+
+impl ImzmlConfig {
+    /// the bytes of an array as stored in the .ibd file
+    fn encode(&self, bytes: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+        if !self.zlib {
+            return Ok(bytes);
+        }
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&bytes)?;
+        encoder.finish()
+    }
+}
+
+/// the m/z array shared by the spectra in continuous mode
+pub struct SharedAxis {
+    pub bins: usize,
+    pub len: usize,
+    pub offset: usize,
+    pub enc_len: usize,
+}
+
 pub struct IMZMLMaker {
     pub image: image::Image,
+    pub config: ImzmlConfig,
     pub header: IMZMLHeader,
     pub ibd_file: std::fs::File,
     pub imzml_file: std::fs::File,
     pub index: usize,  // counter that imzml requires as an index for each spectrum
     pub offset: usize, // keeps track of the offset in the .ibd file for imzml
-    pub low_crop_row: usize, // if no crop, make 0
-    pub high_crop_row: usize, // if no crop, make super large
-    pub low_crop_col: usize, // if no crop, make 0
-    pub high_crop_col: usize, // if no crop, make super large
+    pub crop_cols: Range<usize>,         // the exported columns of the image
+    pub crop_rows: Range<usize>,         // the exported rows of the image
+    pub shared_axis: Option<SharedAxis>, // written once in continuous mode
+    pub imzml_offset: u64,               // the length of the .imzml file written so far
+    pub header_len: usize,               // as first written, the rewritten header is padded to it
+    pub spectrum_offsets: Vec<u64>,      // where each spectrum starts in the .imzml file
+    pub peaks: Vec<(i64, f64)>,          // apex (ps) and m/z of the found peaks in centroid mode
+    pub datacube: Option<datacube::Datacube>, // if set, filled in the same pass over the events
+}
+
+/// processed spectra only hold the events below this m/z
+const PROCESSED_MZ_MAX: f32 = 300.0;
+
+/// the value an event is stored as in the spectrum of its pixel: its m/z (processed), its bin
+/// (continuous) or its found peak (centroid, of apexes in increasing order); None for the events
+/// the mode leaves out
+pub(crate) fn event_value(mode: Mode, cfg: &image::Config, tof_ps: i64, apexes: &[i64]) -> Option<f32> {
+    match mode {
+        Mode::Processed => {
+            Some(cfg.calibration.time_to_mass(tof_ps) as f32).filter(|&mz| mz > 0.0 && mz < PROCESSED_MZ_MAX)
+        }
+        Mode::Continuous => {
+            let tof_max = cfg.spectrum.tof_max(Some(cfg.tof_pulse_length));
+            cfg.spectrum.index(tof_ps, tof_max, &cfg.calibration).map(|bin| bin as f32)
+        }
+        Mode::Centroid => nearest_peak(apexes, tof_ps, cfg.peak_time_window).map(|peak| peak as f32),
+    }
+}
+
+/// the peak (of apexes in increasing order) with the apex closest to a time, if within the window
+/// (from `window` before the apex, inclusive, to `window` after it, exclusive, like the ion images)
+fn nearest_peak(apexes: &[i64], tof: i64, window: i64) -> Option<usize> {
+    let after = apexes.partition_point(|&apex| apex < tof);
+    let distance = |i: usize| (apexes[i] - tof).abs();
+    let candidates = after.checked_sub(1).into_iter().chain(Some(after).filter(|&i| i < apexes.len()));
+    candidates.min_by_key(|&i| distance(i)).filter(|&i| (-window..window).contains(&(tof - apexes[i])))
 }
 
 impl IMZMLMaker {
-    pub fn new(image: image::Image) -> Result<IMZMLMaker, Box<dyn Error>> {
-        let low_crop_row = 140 / 5; // if no crop, make 0
-        let high_crop_row = 1265 / 5; // if no crop, make super large
-        let low_crop_col = 155 / 5; // if no crop, make 0
-        let high_crop_col = 2025 / 5; // if no crop, make super large
-        let low_crop_row = 0;
-        let high_crop_row = 10000;
-        let low_crop_col = 0;
-        let high_crop_col = 10000;
-        let (xs, ys) = (image.config.cols(), image.config.rows());
+    pub fn new(image: image::Image, config: ImzmlConfig) -> Result<IMZMLMaker, Box<dyn Error>> {
+        let (cols, rows) = (image.config.cols() as usize, image.config.rows() as usize);
+        let (crop_cols, crop_rows) = match config.crop {
+            Some(crop) => crop.ranges(image.config.pixels_per_mm, cols, rows)?,
+            None => (0..cols, 0..rows),
+        };
+        let (xs, ys) = (crop_cols.len(), crop_rows.len());
         let pixel_size = 1000.0 / image.config.pixels_per_mm;
-        if low_crop_row > 0 && low_crop_col > 0 {
-            assert!(high_crop_col < xs && high_crop_row < ys);
-            assert!(low_crop_col < high_crop_col && low_crop_row < high_crop_row);
-            let (xs, ys) = (high_crop_col - low_crop_col, high_crop_row - low_crop_row);
-        }
+        let (mode, obo_codes_mode) = match config.mode {
+            Mode::Processed | Mode::Centroid => ("processed", "IMS:1000031"),
+            Mode::Continuous => ("continuous", "IMS:1000030"),
+        };
+        let (spectrum_type, obo_codes_spectrum_type) = match config.mode {
+            Mode::Centroid => ("centroid spectrum", "MS:1000127"),
+            _ => ("profile spectrum", "MS:1000128"),
+        };
+        let mut peaks: Vec<(i64, f64)> = match (config.mode, &image.meta.found_peaks) {
+            (Mode::Centroid, None) => return Err("centroid spectra need the found peaks of the image".into()),
+            (Mode::Centroid, Some(found)) => {
+                found.iter().map(|p| (p.apex, image.config.calibration.time_to_mass(p.apex))).collect()
+            }
+            _ => vec![],
+        };
+        peaks.sort_by_key(|&(apex, _)| apex);
+        let (mz_data_type, obo_codes_mz_data_type) = config.mz_type.cv();
+        let (int_data_type, obo_codes_int_data_type) = config.intensity_type.cv();
+        let (compression, obo_codes_compression) = match config.zlib {
+            true => ("zlib compression", "MS:1000574"),
+            false => ("no compression", "MS:1000576"),
+        };
         let header = IMZMLHeader {
             uuid: v4!().replace('-', ""),
             x_pixel_maximum: format!("{xs}"),
@@ -60,176 +378,284 @@ impl IMZMLMaker {
             height_micron: format!("{}", (ys as f64 * pixel_size) as u32),
             x_pixel_size: format!("{pixel_size}"), // pixel size in micrometers as floating
             y_pixel_size: format!("{pixel_size}"), // pixel size in micrometers as floating
-            number_of_spectra: format!("{}", xs * ys), // The total number of "spectra" or pixels
+            number_of_spectra: format!("{}", xs * ys), // at most, the written ones are counted at the end
+            mode: mode.to_string(),
+            obo_codes_mode: obo_codes_mode.to_string(),
+            spectrum_type: spectrum_type.to_string(),
+            obo_codes_spectrum_type: obo_codes_spectrum_type.to_string(),
+            mz_data_type: mz_data_type.to_string(),
+            obo_codes_mz_data_type: obo_codes_mz_data_type.to_string(),
+            int_data_type: int_data_type.to_string(),
+            obo_codes_int_data_type: obo_codes_int_data_type.to_string(),
+            mz_compression: compression.to_string(),
+            obo_codes_mz_compression: obo_codes_compression.to_string(),
+            int_compression: compression.to_string(),
+            obo_codes_int_compression: obo_codes_compression.to_string(),
+            instrument: config.instrument.clone(),
+            indexed: config.indexed,
             ..Default::default()
         };
         let ibd_file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(image.tpx3_path.with_extension("ibd"))?;
-        let imzml_file = std::fs::File::create(image.tpx3_path.with_extension("imzml"))?;
+        let imzml_file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(image.tpx3_path.with_extension("imzml"))?;
         Ok(IMZMLMaker {
             image,
+            config,
             header,
             ibd_file,
             imzml_file,
             index: 0,
             offset: 16,
-            low_crop_row: low_crop_row as usize,
-            high_crop_row: high_crop_row as usize,
-            low_crop_col: low_crop_col as usize,
-            high_crop_col: high_crop_col as usize,
+            crop_cols,
+            crop_rows,
+            shared_axis: None,
+            imzml_offset: 0,
+            header_len: 0,
+            spectrum_offsets: vec![],
+            peaks,
+            datacube: None,
         })
     }
 
+    /// appends to the .imzml file, keeping track of its length
+    fn write_imzml(&mut self, text: &str) -> Result<(), std::io::Error> {
+        self.imzml_file.write_all(text.as_bytes())?;
+        self.imzml_offset += text.len() as u64;
+        Ok(())
+    }
+
+    /// writes the start of the files: the header, the uuid and, in continuous mode, the m/z array
+    /// of the bin centers that all spectra refer to
+    pub fn write_start(&mut self) -> Result<(), Box<dyn Error>> {
+        let header = self.header.to_string();
+        self.header_len = header.len();
+        self.write_imzml(&header)?;
+        self.ibd_file.write_all(&self.uuid_as_bytes()?)?; // first 16 bytes
+        if self.config.mode == Mode::Continuous {
+            let cfg = &self.image.config;
+            let spectrum_config = &cfg.spectrum;
+            let bins = spectrum_config.bins(spectrum_config.tof_max(Some(cfg.tof_pulse_length)), &cfg.calibration);
+            if bins >= 1 << 24 {
+                return Err(format!("{} bins are too many for continuous spectra, use wider bins", bins).into());
+            }
+            let mut axis = mass::Spectrum::new(spectrum_config);
+            axis.counts = vec![0; bins];
+            let mzs: Vec<f64> = axis
+                .axis()
+                .iter()
+                .map(|edge| edge + axis.width() / 2.0)
+                .map(|center| match spectrum_config.binning {
+                    mass::Binning::Time { .. } => cfg.calibration.time_to_mass(center as i64),
+                    mass::Binning::Mass { .. } => center,
+                })
+                .collect();
+            let mzs_bytes = self.config.encode(self.config.mz_type.to_bytes(&mzs))?;
+            self.ibd_file.write_all(&mzs_bytes)?;
+            self.shared_axis = Some(SharedAxis { bins, len: mzs.len(), offset: self.offset, enc_len: mzs_bytes.len() });
+            self.offset += mzs_bytes.len();
+        }
+        Ok(())
+    }
+
     /// turns the header uuid into a set of bytes to write
     pub fn uuid_as_bytes(&self) -> Result<Vec<u8>, ParseIntError> {
         (0..32).step_by(2).map(|i| u8::from_str_radix(&self.header.uuid[i..i + 2], 16)).collect()
     }
 
     /// generates a sha1 checksum for the ibd file -> only call this after IBD has been written!!
-    pub fn ibd_to_sha1(&mut self) -> Result<String, Box<dyn Error>> {
-        const BUFFER_SIZE: usize = 1024;
-        let (mut sh, mut buffer) = (Sha1::default(), [0u8; BUFFER_SIZE]);
-        self.ibd_file.seek(SeekFrom::Start(0))?;
-        // maybe I need to panic here instead of using while let...
-        while let Ok(bytes_read) = self.ibd_file.read(&mut buffer) {
-            sh.update(&buffer[..bytes_read]);
-            if bytes_read < BUFFER_SIZE {
-                break;
-            }
-        }
-        Ok(sh.finalize().iter().map(|b| format!("{:02x}", b)).collect())
-    }
+    pub fn ibd_to_sha1(&mut self) -> Result<String, Box<dyn Error>> { file_to_sha1(&mut self.ibd_file) }
 
-    /// streams through a TPX3Reader, rasterizing it and converting it to imzml
+    /// streams through a TPX3Reader, rasterizing it and converting it to imzml; rows and columns
+    /// are numbered within the crop and the rows are written in order once all events are read;
+    /// the datacube (if set) gets every event of the uncropped image
     pub fn stream_convert_and_save(&mut self) -> Result<(), Box<dyn Error>> {
         let reader = TPX3Reader::new(&self.image.tpx3_path)?;
-        let col_count = self.image.config.cols() as usize;
-        let row_count = self.image.config.rows() as usize;
+        let (crop_cols, crop_rows) = (self.crop_cols.clone(), self.crop_rows.clone());
         let coords = self.image.meta.coordinates.take().ok_or("Coordinates not present!")?;
-        self.imzml_file.write_all(self.header.to_string().as_bytes());
-        self.ibd_file.write_all(&self.uuid_as_bytes()?); // first 16 bits
-        let mut spans: HashMap<usize, PixelSpan> = HashMap::new(); // key is row index
-        let mut rows_written: Vec<usize> = vec![];
+        self.write_start()?;
+        let cfg = self.image.config;
+        // rows are accumulated (spilled to files beyond the memory budget) and written at the end
+        let spill_dir = self.image.tpx3_path.with_extension("imzml_spill");
+        let mut store = RowStore::new(spill_dir, crop_cols.len(), self.config.memory_budget_mb << 20)?;
         let mut direction = Direction::Right; // to determine if there is a new pass
         let mut count = 0;
         let mut max_pixel = 0;
         let dead_pix = self.image.meta.dead_pixels.clone();
         let dead_pix = dead_pix.as_ref().unwrap();
+        let apexes: Vec<i64> = self.peaks.iter().map(|&(apex, _)| apex).collect();
         for (pulse, coordinates) in reader.zip(&coords).filter(|(_, c)| c.is_not_inf()) {
-            for hit in pulse.hits.iter().filter(|h| !h.is_dead(dead_pix)) {
-                let (col, row) = hit.rasterize(&self.image.config, coordinates);
-                let tof_ps = (hit.toa - pulse.time) % self.image.config.tof_pulse_length;
-                let mz = mass::time_to_mass(tof_ps) as f32;
-                if row < row_count && col < col_count && mz > 0.0 && mz < 300.0 {
-                    let row = spans.entry(row).or_insert_with(|| PixelSpan::empty(col_count));
-                    row.add_mz(mz, col);
+            for hit in pulse.hits.iter().filter(|h| h.is_counted(dead_pix)) {
+                if let Some(cube) = self.datacube.as_mut() {
+                    cube.add(hit, pulse.time, coordinates);
+                }
+                let (col, row) = hit.rasterize(&cfg, coordinates);
+                let tof_ps = (hit.toa - pulse.time) % cfg.tof_pulse_length;
+                let value = event_value(self.config.mode, &cfg, tof_ps, &apexes);
+                if let Some(value) = value.filter(|_| crop_rows.contains(&row) && crop_cols.contains(&col)) {
+                    store.add(row - crop_rows.start, col - crop_cols.start, value)?;
                 }
             }
             if coordinates.direction != direction {
                 direction = coordinates.direction;
-                spans.iter_mut().for_each(|(_, pixel_span)| pixel_span.update_end_pass());
-                let mut finished_rows: Vec<usize> =
-                    spans.iter().filter(|(_, v)| v.empty_pass_count > 2).map(|(k, _)| *k).collect();
-                finished_rows.sort_unstable();
-                let max_pix =
-                    self.scan_write_rows(&mut spans, &mut rows_written, &finished_rows)?;
-                if max_pix > max_pixel {
-                    max_pixel = max_pix
+                store.end_pass();
+                if let Some(cube) = self.datacube.as_mut() {
+                    cube.end_pass()?;
                 }
             }
             count += 1;
         }
         println!("rows read: {count}");
-        let mut final_rows: Vec<usize> = spans.iter().map(|(k, _)| *k).collect();
-        final_rows.sort_unstable();
-        let max_pix = self.scan_write_rows(&mut spans, &mut rows_written, &final_rows)?;
-        if max_pix > max_pixel {
-            max_pixel = max_pix;
+        if let Some(cube) = self.datacube.take() {
+            cube.finish()?;
+        }
+        for row in 0..crop_rows.len() {
+            let max_pix = self.write_row(&mut store.take(row)?, row)?;
+            if max_pix > max_pixel {
+                max_pixel = max_pix;
+            }
         }
         println!("The maximum intensity of a pixel is {}", max_pixel);
-        self.imzml_file.write_all(IMZML_FOOTER.to_string().as_bytes());
-        self.overwrite_header_with_sha1_checksum()?;
+        let mb = |bytes: f64| bytes / (1 << 20) as f64;
+        println!(
+            "the rows held up to {:.1} MB in memory (budget {} MB), rows were spilled {} times ({:.1} MB)",
+            mb(store.peak as f64),
+            self.config.memory_budget_mb,
+            store.spilled_rows,
+            mb(store.spilled_bytes as f64)
+        );
+        store.finish()?;
+        self.write_end()?;
         Ok(())
     }
 
-    pub fn scan_write_rows(
-        &mut self, spans: &mut HashMap<usize, PixelSpan>, rows_written: &mut Vec<usize>,
-        rows: &[usize],
-    ) -> Result<usize, Box<dyn Error>> {
+    /// writes every pixel of a row (numbered within the crop); with `skip_empty` only the pixels
+    /// with events are written
+    pub fn write_row(&mut self, span: &mut PixelSpan, row: usize) -> Result<usize, Box<dyn Error>> {
         let mut max_pixel = 0;
-        for &row in rows.iter() {
-            if rows_written.iter().any(|&i| i == row) {
-                panic!("Attempting to write row {} twice!", row);
+        for (col, pixel) in span.pixels.iter_mut().enumerate() {
+            if self.config.skip_empty && pixel.is_empty() {
+                continue;
             }
-            rows_written.push(row); // this catch won't work now probably due to the cropping; TODO: Update this
-            let mut extracted_row = spans.remove(&row).ok_or("no row to remove!")?;
-            if row >= self.low_crop_row && row < self.high_crop_row {
-                extracted_row.pixels.iter_mut().enumerate().for_each(|(col, pixel)| {
-                    if col >= self.low_crop_col && col < self.high_crop_col {
-                        let max = self.write_spectrum(
-                            pixel,
-                            col - self.low_crop_col,
-                            row - self.low_crop_row,
-                        );
-                        if max > max_pixel {
-                            max_pixel = max; // this is just a counter for printing not something used in logic
-                        }
-                    }
-                });
+            let max = self.write_spectrum(pixel, col, row)?;
+            if max > max_pixel {
+                max_pixel = max; // this is just a counter for printing not something used in logic
             }
         }
         Ok(max_pixel)
     }
 
-    pub fn write_spectrum(&mut self, pixel: &mut Pixel, col: usize, row: usize) -> usize {
-        let (mzs, ints): (Vec<f32>, Vec<i16>) = pixel.to_vecs();
+    /// writes the spectrum of a pixel; in continuous mode it refers to the shared m/z array and
+    /// only the counts of every bin are written, in centroid mode only the peaks with counts
+    pub fn write_spectrum(
+        &mut self, pixel: &mut Pixel, col: usize, row: usize,
+    ) -> Result<usize, Box<dyn Error>> {
+        let (ints, mz_len, mz_offset, mz_enc_len, mz_written) = match &self.shared_axis {
+            Some(axis) => (pixel.to_counts(axis.bins), axis.len, axis.offset, axis.enc_len, 0),
+            None => {
+                let (mzs, ints): (Vec<f64>, Vec<u32>) = match self.config.mode {
+                    Mode::Centroid => {
+                        let counts = pixel.to_counts(self.peaks.len());
+                        counts.iter().zip(&self.peaks).filter(|(&c, _)| c > 0).map(|(&c, &(_, mz))| (mz, c)).unzip()
+                    }
+                    _ => {
+                        let (mzs, ints) = pixel.to_vecs();
+                        (mzs.iter().map(|&m| m as f64).collect(), ints)
+                    }
+                };
+                let mzs_bytes = self.config.encode(self.config.mz_type.to_bytes(&mzs))?;
+                self.ibd_file.write_all(&mzs_bytes)?;
+                (ints, mzs.len(), self.offset, mzs_bytes.len(), mzs_bytes.len())
+            }
+        };
         let maximum_int = *ints.iter().max().unwrap_or(&0) as usize;
-        let mzs_bytes: Vec<u8> = mzs.iter().flat_map(|m| m.to_le_bytes()).collect();
-        let ints_bytes: Vec<u8> = ints.iter().flat_map(|i| i.to_le_bytes()).collect();
-        let reverse_ints_bytes: Vec<i16> = ints_bytes
-            .chunks(2)
-            .map(|i| i16::from_le_bytes(i.try_into().expect("slice with incorrect length")))
-            .collect::<Vec<i16>>();
-        for &i in reverse_ints_bytes.iter() {
-            assert!(i > 0);
-        }
-        self.ibd_file.write_all(&mzs_bytes);
-        self.ibd_file.write_all(&ints_bytes);
-        let (mz_enc_len, int_enc_len) = (mzs_bytes.len(), ints_bytes.len());
+        let ints_bytes = self.config.encode(self.config.intensity_type.to_bytes(&ints))?;
+        self.ibd_file.write_all(&ints_bytes)?;
+        let int_enc_len = ints_bytes.len();
         let spectrum = IMZMLSpectrum {
             index: self.index,
-            spectrum_sum: ints.iter().sum::<i16>() as u16,
+            spectrum_sum: ints.iter().map(|&i| self.config.intensity_type.saturate(i) as u64).sum(), // as stored
             pixel_column: (col + 1) as u32, // we add 1 due to IMZML spec
             pixel_row: (row + 1) as u32,    // we add 1 due to IMZML spec
-            mz_len: mzs.len(),
-            mz_offset: self.offset, // starting offset
+            mz_len,
+            mz_offset, // starting offset
             mz_enc_len,
             int_len: ints.len(),
-            int_offset: self.offset + mz_enc_len,
+            int_offset: self.offset + mz_written,
             int_enc_len,
         };
-        self.imzml_file.write_all(spectrum.to_string().as_bytes());
-        self.offset = self.offset + mz_enc_len + int_enc_len;
+        self.spectrum_offsets.push(self.imzml_offset);
+        self.write_imzml(&spectrum.to_string())?;
+        self.offset = self.offset + mz_written + int_enc_len;
         self.index += 1;
-        maximum_int
+        Ok(maximum_int)
     }
 
     /// once everything is finished with the .ibd file, we need to fill in a correct checksum
-    /// from our dummy checksum; although this is wasteful, the headers are pretty small and so
-    /// overwriting them is a bit easier than finding the checksum and just overwriting that
+    /// from our dummy checksum (and the number of spectra written); although this is wasteful, the
+    /// headers are pretty small and so overwriting them is a bit easier than finding the checksum
+    /// and just overwriting that; a shorter header is padded with spaces after its last element
     pub fn overwrite_header_with_sha1_checksum(&mut self) -> Result<(), Box<dyn Error>> {
         self.header.sha1sum = self.ibd_to_sha1()?; // add real checksum to header struct
+        self.header.number_of_spectra = format!("{}", self.index);
         println!("checksum: {}", self.header.sha1sum);
-        let overwrite_header = self.header.to_string(); // regenerate header string
+        let mut overwrite_header = self.header.to_string(); // regenerate header string
+        let padding = self.header_len.checked_sub(overwrite_header.len()).ok_or("the rewritten header is longer")?;
+        overwrite_header.insert_str(overwrite_header.len() - 1, &" ".repeat(padding)); // before the last newline
         self.imzml_file.seek(SeekFrom::Start(0))?;
         self.imzml_file.write_all(overwrite_header.as_bytes())?; // write it to the start
+        self.imzml_file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// closes the spectrum list and, for indexedmzML, appends the offset of every spectrum, the
+    /// offset of that index and the SHA-1 of the file up to and including "<fileChecksum>" (so
+    /// after the ibd checksum is filled in)
+    pub fn write_end(&mut self) -> Result<(), Box<dyn Error>> {
+        self.write_imzml(IMZML_FOOTER)?;
+        self.write_imzml("\n")?;
+        if !self.config.indexed {
+            return self.overwrite_header_with_sha1_checksum();
+        }
+        let index_offset = self.imzml_offset;
+        let mut index = std::io::BufWriter::new(&self.imzml_file);
+        write!(index, "<indexList count=\"1\">\n    <index name=\"spectrum\">\n")?;
+        for (i, offset) in self.spectrum_offsets.iter().enumerate() {
+            writeln!(index, "        <offset idRef=\"Scan={}\">{}</offset>", i + 1, offset)?;
+        }
+        write!(index, "    </index>\n</indexList>\n<indexListOffset>{}</indexListOffset>\n", index_offset)?;
+        write!(index, "<fileChecksum>")?;
+        index.flush()?;
+        drop(index);
+        self.overwrite_header_with_sha1_checksum()?;
+        let checksum = file_to_sha1(&mut self.imzml_file)?;
+        self.imzml_file.seek(SeekFrom::End(0))?;
+        self.imzml_file.write_all(format!("{}</fileChecksum>\n</indexedmzML>\n", checksum).as_bytes())?;
         Ok(())
     }
 }
 
+/// the hex SHA-1 of a whole file
+pub(crate) fn file_to_sha1(file: &mut std::fs::File) -> Result<String, Box<dyn Error>> {
+    let (mut sh, mut buffer) = (Sha1::default(), [0u8; 1 << 16]);
+    file.seek(SeekFrom::Start(0))?;
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            bytes_read => sh.update(&buffer[..bytes_read]),
+        }
+    }
+    Ok(sh.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
 
 pub struct IMZMLHeader {
     uuid: String,
@@ -256,12 +682,14 @@ pub struct IMZMLHeader {
     obo_codes_line_scan_direction: String,
     mode: String,
     obo_codes_mode: String,
+    spectrum_type: String,
+    obo_codes_spectrum_type: String,
     mz_compression: String,
     obo_codes_mz_compression: String,
     int_compression: String,
     obo_codes_int_compression: String,
-    polarity: String,
-    obo_codes_polarity: String,
+    instrument: Instrument,
+    indexed: bool, // wrapped in an indexedmzML element
 }
 
 
@@ -294,25 +722,57 @@ impl Default for IMZMLHeader {
             obo_codes_line_scan_direction: "IMS:1000491".to_string(),
             mode: "processed".to_string(),
             obo_codes_mode: "IMS:1000031".to_string(),
+            spectrum_type: "profile spectrum".to_string(),
+            obo_codes_spectrum_type: "MS:1000128".to_string(),
             mz_compression: "no compression".to_string(),
             obo_codes_mz_compression: "MS:1000576".to_string(),
             int_compression: "no compression".to_string(),
             obo_codes_int_compression: "MS:1000576".to_string(),
-            polarity: "positive scan".to_string(), // "negative scan"
-            obo_codes_polarity: "MS:1000130".to_string(), // "MS:1000129"
+            instrument: Instrument::default(),
+            indexed: false,
         }
     }
 }
 
-/// These parameters satisfy the IMZML specification but are not necessarily correct for our instrument. TODO: update with correct instrument specifications.
+/// the instrument, sample and operator come from `Instrument`
 impl std::fmt::Display for IMZMLHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let instrument = &self.instrument;
+        let (polarity, obo_codes_polarity) = match instrument.polarity {
+            Polarity::Positive => ("positive scan", "MS:1000130"),
+            Polarity::Negative => ("negative scan", "MS:1000129"),
+        };
+        let contact = match instrument.operator.is_empty() {
+            true => String::new(),
+            false => {
+                let mut contact = vec![CvParam::new("MS:1000586", "contact name", &instrument.operator)];
+                if !instrument.affiliation.is_empty() {
+                    contact.push(CvParam::new("MS:1000590", "contact affiliation", &instrument.affiliation));
+                }
+                format!("    <contact>\n{}    </contact>\n", params_to_xml(&contact, 8))
+            }
+        };
+        let indexed = match self.indexed {
+            true => concat!(
+                r#"<indexedmzML xmlns="http://psi.hupo.org/ms/mzml" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" "#,
+                r#"xsi:schemaLocation="http://psi.hupo.org/ms/mzml http://psidev.info/files/ms/mzML/xsd/mzML1.1.2_idx.xsd">"#,
+                "\n"
+            ),
+            false => "",
+        };
+        let sample_list = match instrument.sample.is_empty() {
+            true => String::new(),
+            false => format!(
+                "<sampleList count=\"1\">\n    <sample id=\"sample1\" name=\"{}\">\n{}    </sample>\n</sampleList>\n",
+                escape_xml(&instrument.sample),
+                params_to_xml(&[CvParam::new("MS:1000002", "sample name", &instrument.sample)], 8)
+            ),
+        };
         write!(
             f,
-            r#"<?xml version="1.0" encoding="ISO-8859-1"?>
-<mzML xmlns="http://psi.hupo.org/ms/mzml" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://psi.hupo.org/ms/mzml http://psidev.info/files/ms/mzML/xsd/mzML1.1.0_idx.xsd" version="1.1">
-    <cvList count="2">
-        <cv URI="http://ontologies.berkeleybop.org/pato.obo" fullName="Phenotype And Trait Ontology" id="PATO" version="releases/2017-07-10"/>
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+{indexed}<mzML xmlns="http://psi.hupo.org/ms/mzml" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://psi.hupo.org/ms/mzml http://psidev.info/files/ms/mzML/xsd/mzML1.1.0_idx.xsd" version="1.1">
+    <cvList count="3">
         <cv URI="http://ontologies.berkeleybop.org/uo.obo" fullName="Units of Measurement Ontology" id="UO" version="releases/2017-09-25"/>
         <cv URI="https://raw.githubusercontent.com/hupo-psi/psi-ms-cv/master/psi-ms.obo" fullName="Proteomics Standards Initiative Mass Spectrometry Ontology" id="MS" version="4.1.0"/>
         <cv URI="https://raw.githubusercontent.com/imzML/imzML/master/imagingMS.obo" fullName="Mass Spectrometry Imaging Ontology" id="IMS" version="1.1.0"/>
@@ -320,12 +780,12 @@ impl std::fmt::Display for IMZMLHeader {
 <fileDescription>
     <fileContent>
         <cvParam cvRef="MS" accession="MS:1000579" name="MS1 spectrum" value=""/>
-        <cvParam cvRef="MS" accession="MS:1000128" name="profile spectrum" value=""/>
+        <cvParam cvRef="MS" accession="{obo_codes_spectrum_type}" name="{spectrum_type}" value=""/>
         <cvParam cvRef="IMS" accession="{obo_codes_mode}" name="{mode}" value=""/>
         <cvParam cvRef="IMS" accession="IMS:1000080" name="universally unique identifier" value="{uuid}"/>
         <cvParam cvRef="IMS" accession="IMS:1000091" name="ibd SHA-1" value="{sha1sum}"/>
     </fileContent>
-</fileDescription>
+{contact}</fileDescription>
 <referenceableParamGroupList count="4">
     <referenceableParamGroup id="mzArray">
         <cvParam cvRef="MS" accession="{obo_codes_mz_compression}" name="{mz_compression}" value=""/>
@@ -334,7 +794,7 @@ impl std::fmt::Display for IMZMLHeader {
         <cvParam cvRef="IMS" accession="IMS:1000101" name="external data" value="true"/>
     </referenceableParamGroup>
     <referenceableParamGroup id="intensityArray">
-        <cvParam cvRef="IMS" accession="{obo_codes_int_data_type}" name="{int_data_type}" value=""/>
+        <cvParam cvRef="{int_data_type_cv}" accession="{obo_codes_int_data_type}" name="{int_data_type}" value=""/>
         <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" unitCvRef="MS" unitAccession="MS:1000131" unitName="number of detector counts"/>
         <cvParam cvRef="MS" accession="{obo_codes_int_compression}" name="{int_compression}" value=""/>
         <cvParam cvRef="IMS" accession="IMS:1000101" name="external data" value="true"/>
@@ -345,12 +805,12 @@ impl std::fmt::Display for IMZMLHeader {
     </referenceableParamGroup>
     <referenceableParamGroup id="spectrum1">
         <cvParam cvRef="MS" accession="MS:1000579" name="MS1 spectrum" value=""/>
-        <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="0"/>
-        <cvParam cvRef="MS" accession="MS:1000128" name="profile spectrum" value=""/>
+        <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1"/>
+        <cvParam cvRef="MS" accession="{obo_codes_spectrum_type}" name="{spectrum_type}" value=""/>
         <cvParam cvRef="MS" accession="{obo_codes_polarity}" name="{polarity}" value=""/>
     </referenceableParamGroup>
 </referenceableParamGroupList>
-<softwareList count="1">
+{sample_list}<softwareList count="1">
     <software id="tpx3_to_imzml" version="0.1">
         <cvParam cvRef="MS" accession="MS:1000799" name="custom unreleased software tool" value="tpx3 to imzml converter"/>
     </software>
@@ -371,30 +831,13 @@ impl std::fmt::Display for IMZMLHeader {
 </scanSettingsList>
 <instrumentConfigurationList count="1">
     <instrumentConfiguration id="IC1">
-        <cvParam cvRef="MS" accession="MS:1000557" name="Trift II BioTRIFT"/>
-        <cvParam cvRef="MS" accession="MS:1000529" name="instrument serial number" value="none"/>
-        <componentList count="3">
+{model}        <componentList count="3">
         <source order="1">
-            <cvParam cvRef="MS" accession="MS:1000073" name="electrospray ionization"/>
-            <cvParam cvRef="MS" accession="MS:1000485" name="nanospray inlet"/>
-            <cvParam cvRef="MS" accession="MS:1000844" name="focus diameter x" value="10.0"/>
-            <cvParam cvRef="MS" accession="MS:1000845" name="focus diameter y" value="10.0"/>
-            <cvParam cvRef="MS" accession="MS:1000846" name="pulse energy" value="10.0"/>
-            <cvParam cvRef="MS" accession="MS:1000847" name="pulse duration" value="10.0"/>
-            <cvParam cvRef="MS" accession="MS:1000848" name="attenuation" value="50.0"/>
-            <cvParam cvRef="MS" accession="MS:1000850" name="gas laser"/>
-            <cvParam cvRef="MS" accession="MS:1000836" name="dried droplet MALDI matrix preparation"/>
-            <cvParam cvRef="MS" accession="MS:1000835" name="matrix solution concentration" value="10.0"/>
-            <cvParam cvRef="MS" accession="MS:1000834" name="matrix solution" value="DHB"/>
-        </source>
+{source}        </source>
         <analyzer order="2">
-            <cvParam cvRef="MS" accession="MS:1000264" name="ion trap"/>
-            <cvParam cvRef="MS" accession="MS:1000014" name="accuracy" value="0.0" unitCvRef="MS" unitAccession="MS:1000040" unitName="m/z"/>
-        </analyzer>
+{analyzer}        </analyzer>
         <detector order="3">
-            <cvParam cvRef="MS" accession="MS:1000253" name="electron multiplier"/>
-            <cvParam cvRef="MS" accession="MS:1000120" name="transient recorder"/>
-        </detector>
+{detector}        </detector>
         </componentList>
     </instrumentConfiguration>
 </instrumentConfigurationList>
@@ -408,6 +851,13 @@ impl std::fmt::Display for IMZMLHeader {
 <run defaultInstrumentConfigurationRef="IC1" id="{run_id}">
     <spectrumList count="{number_of_spectra}" defaultDataProcessingRef="export_from_tpx3_to_imzml">
 "#,
+            indexed = indexed,
+            contact = contact,
+            sample_list = sample_list,
+            model = params_to_xml(&instrument.model, 8),
+            source = params_to_xml(&instrument.source, 12),
+            analyzer = params_to_xml(&instrument.analyzer, 12),
+            detector = params_to_xml(&instrument.detector, 12),
             uuid = self.uuid,
             sha1sum = self.sha1sum,
             x_pixel_maximum = self.x_pixel_maximum,
@@ -422,6 +872,7 @@ impl std::fmt::Display for IMZMLHeader {
             obo_codes_mz_data_type = self.obo_codes_mz_data_type,
             int_data_type = self.int_data_type,
             obo_codes_int_data_type = self.obo_codes_int_data_type,
+            int_data_type_cv = self.obo_codes_int_data_type.split(':').next().unwrap_or("MS"),
             scan_direction = self.scan_direction,
             obo_codes_scan_direction = self.obo_codes_scan_direction,
             scan_pattern = self.scan_pattern,
@@ -432,12 +883,14 @@ impl std::fmt::Display for IMZMLHeader {
             obo_codes_line_scan_direction = self.obo_codes_line_scan_direction,
             mode = self.mode,
             obo_codes_mode = self.obo_codes_mode,
+            spectrum_type = self.spectrum_type,
+            obo_codes_spectrum_type = self.obo_codes_spectrum_type,
             mz_compression = self.mz_compression,
             obo_codes_mz_compression = self.obo_codes_mz_compression,
             int_compression = self.int_compression,
             obo_codes_int_compression = self.obo_codes_int_compression,
-            polarity = self.polarity,
-            obo_codes_polarity = self.obo_codes_polarity
+            polarity = polarity,
+            obo_codes_polarity = obo_codes_polarity
         )
     }
 }
@@ -445,7 +898,7 @@ impl std::fmt::Display for IMZMLHeader {
 
 pub struct IMZMLSpectrum {
     index: usize,
-    spectrum_sum: u16, // sum of intensities in spectrum
+    spectrum_sum: u64, // sum of intensities in spectrum
     pixel_column: u32,
     pixel_row: u32,
     mz_len: usize,      // 8399
@@ -502,3 +955,249 @@ impl std::fmt::Display for IMZMLSpectrum {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the .imzml and .ibd files of a test, removed when it ends (also when it fails)
+    struct Written(std::path::PathBuf);
+
+    impl Drop for Written {
+        fn drop(&mut self) {
+            std::fs::remove_file(self.0.with_extension("ibd")).ok();
+            std::fs::remove_file(self.0.with_extension("imzml")).ok();
+        }
+    }
+
+    /// a maker that has written the header of "two_grids_imzml_<name>" files in the temporary
+    /// directory
+    fn start(
+        name: &str, config: image::Config, meta: image::Metadata, imzml_config: ImzmlConfig,
+    ) -> (IMZMLMaker, Written) {
+        let path = std::env::temp_dir().join(format!("two_grids_imzml_{}_{}.tpx3c", name, std::process::id()));
+        let image = image::Image { tpx3_path: path.clone(), config, meta };
+        let mut maker = IMZMLMaker::new(image, imzml_config).unwrap();
+        maker.write_start().unwrap();
+        (maker, Written(path))
+    }
+
+    #[test]
+    fn continuous_spectra_share_one_mz_array() {
+        let mut config = image::Config { width: 0.1, height: 0.1, tof_pulse_length: 50_000_000, ..Default::default() };
+        let binning = mass::Binning::Time { width: 1_000_000.0 };
+        config.spectrum = mass::SpectrumConfig { binning, tof_min: None, tof_max: Some(10_000_000) };
+        let imzml_config = ImzmlConfig { mode: Mode::Continuous, ..Default::default() };
+        let (mut maker, written) = start("continuous", config, Default::default(), imzml_config);
+        let path = &written.0;
+        let mut pixel = Pixel::empty();
+        pixel.extend([2.0, 7.0, 2.0]); // bins
+        assert_eq!(maker.write_spectrum(&mut pixel, 0, 0).unwrap(), 2);
+        maker.write_spectrum(&mut Pixel::empty(), 1, 0).unwrap();
+
+        let ibd = std::fs::read(path.with_extension("ibd")).unwrap();
+        assert_eq!(ibd.len(), 16 + 10 * 4 + 2 * 10 * 2); // uuid, m/z array, two intensity arrays
+        let mz = |i: usize| f32::from_le_bytes([ibd[16 + 4 * i], ibd[17 + 4 * i], ibd[18 + 4 * i], ibd[19 + 4 * i]]);
+        assert!((mz(3) as f64 - config.calibration.time_to_mass(3_500_000)).abs() < 1e-3); // bin centers
+        let counts: Vec<i16> = ibd[56..76].chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        assert_eq!(counts, vec![0, 0, 2, 0, 0, 0, 0, 1, 0, 0]);
+        let xml = std::fs::read_to_string(path.with_extension("imzml")).unwrap();
+        assert!(xml.contains(r#"accession="IMS:1000030" name="continuous""#));
+        assert_eq!(xml.matches(r#"name="external offset" value="16""#).count(), 2);
+        assert!(xml.contains(r#"value="56""#) && xml.contains(r#"value="76""#));
+    }
+
+    #[test]
+    fn arrays_are_compressed_and_typed_with_a_wide_tic() {
+        let (mz_type, intensity_type) = (MzType::F64, IntensityType::I32);
+        let config = ImzmlConfig { mz_type, intensity_type, zlib: true, ..Default::default() };
+        let (mut maker, written) = start("zlib", Default::default(), Default::default(), config);
+        let path = &written.0;
+        let mut pixel = Pixel::empty();
+        pixel.extend(vec![50.0; 70_000]);
+        pixel.push(60.0);
+        assert_eq!(maker.write_spectrum(&mut pixel, 0, 0).unwrap(), 70_000);
+
+        let ibd = std::fs::read(path.with_extension("ibd")).unwrap();
+        let xml = std::fs::read_to_string(path.with_extension("imzml")).unwrap();
+        let value = |name: &str| -> usize {
+            let start = xml.find(&format!(r#"name="{}" value=""#, name)).unwrap() + name.len() + 15;
+            xml[start..].split('"').next().unwrap().parse().unwrap()
+        };
+        let inflate = |offset: usize, len: usize| {
+            let mut bytes = vec![];
+            flate2::read::ZlibDecoder::new(&ibd[offset..offset + len]).read_to_end(&mut bytes).unwrap();
+            bytes
+        };
+        let mz_enc_len = value("external encoded length"); // the first is of the m/z array
+        let mzs = inflate(16, mz_enc_len);
+        let ints = inflate(16 + mz_enc_len, ibd.len() - 16 - mz_enc_len);
+        assert_eq!(mzs, [50f64.to_le_bytes(), 60f64.to_le_bytes()].concat());
+        assert_eq!(ints, [70_000i32.to_le_bytes(), 1i32.to_le_bytes()].concat());
+        assert_eq!(value("total ion current"), 70_001);
+        assert_eq!(IntensityType::I16.to_bytes(&[70_000]), i16::MAX.to_le_bytes()); // saturates
+        for accession in ["MS:1000574", "MS:1000523", r#"cvRef="MS" accession="MS:1000519""#] {
+            assert!(xml.contains(accession), "{} missing", accession);
+        }
+    }
+
+    #[test]
+    fn tics_sum_the_saturated_counts() {
+        let (mut maker, written) = start("saturated", Default::default(), Default::default(), Default::default());
+        let mut pixel = Pixel::empty();
+        pixel.extend(vec![50.0; 70_000]);
+        pixel.push(60.0);
+        maker.write_spectrum(&mut pixel, 0, 0).unwrap();
+        let xml = std::fs::read_to_string(written.0.with_extension("imzml")).unwrap();
+        let tic = i16::MAX as u32 + 1; // as a reader sums the 16-bit intensities
+        assert!(xml.contains(&format!(r#"name="total ion current" value="{}""#, tic)));
+    }
+
+    #[test]
+    fn headers_describe_the_configured_instrument() {
+        let header = IMZMLHeader::default().to_string();
+        let count = |xml: &str, tag: &str| xml.matches(&format!("<{} ", tag)).count();
+        assert!(header.contains(&format!(r#"<cvList count="{}">"#, count(&header, "cv"))));
+        assert!(header.contains(r#"<cvParam cvRef="MS" accession="MS:1000084" name="time-of-flight" value=""/>"#));
+        assert!(header.contains(r#"<userParam name="camera" value="TPX3CAM (Timepix3)"/>"#));
+        assert!(header.contains(r#"accession="MS:1000393" name="laser desorption ionization""#));
+        assert!(!header.contains("<contact>") && !header.contains("<sampleList"));
+
+        let instrument = Instrument {
+            source: vec![CvParam::new("MS:1000075", "matrix-assisted laser desorption ionization", "")],
+            polarity: Polarity::Negative,
+            sample: String::from("two grids & tape"),
+            operator: String::from("A. Müller"),
+            ..Default::default()
+        };
+        let header = IMZMLHeader { instrument, ..Default::default() }.to_string();
+        assert!(header.contains(r#"accession="MS:1000075" name="matrix-assisted laser desorption ionization""#));
+        assert!(header.contains(r#"<sample id="sample1" name="two grids &amp; tape">"#));
+        assert!(header.starts_with(r#"<?xml version="1.0" encoding="UTF-8"?>"#)); // as the strings are written
+        assert!(header.contains(r#"name="contact name" value="A. Müller""#));
+        assert!(header.contains(r#"accession="MS:1000129" name="negative scan""#));
+        assert_eq!(header.matches("ionization").count(), 1);
+    }
+
+    #[test]
+    fn indexed_files_point_at_their_spectra_and_carry_a_checksum() {
+        let (mut maker, written) = start("index", Default::default(), Default::default(), Default::default());
+        let path = &written.0;
+        let mut pixel = Pixel::empty();
+        pixel.extend([50.0, 60.0]);
+        maker.write_spectrum(&mut pixel, 0, 0).unwrap();
+        maker.write_spectrum(&mut Pixel::empty(), 1, 0).unwrap();
+        maker.write_end().unwrap();
+
+        let xml = std::fs::read_to_string(path.with_extension("imzml")).unwrap();
+        assert!(xml.starts_with("<?xml") && xml.contains("<indexedmzML ") && xml.ends_with("</indexedmzML>\n"));
+        let between = |start: &str, end: &str| {
+            let from = xml.find(start).unwrap() + start.len();
+            &xml[from..from + xml[from..].find(end).unwrap()]
+        };
+        let index_offset: usize = between("<indexListOffset>", "<").parse().unwrap();
+        assert!(xml[index_offset..].starts_with("<indexList "));
+        for scan in 1..=2 {
+            let offset: usize = between(&format!(r#"<offset idRef="Scan={}">"#, scan), "<").parse().unwrap();
+            let spectrum = &xml[offset..];
+            assert!(spectrum.starts_with("<spectrum ") && spectrum.contains(&format!(r#"id="Scan={}""#, scan)));
+            assert!(spectrum.find(&format!(r#"id="Scan={}""#, scan)) < spectrum.find('>'));
+        }
+        let checked = xml.find("<fileChecksum>").unwrap() + "<fileChecksum>".len();
+        let sha1: String = Sha1::digest(&xml.as_bytes()[..checked]).iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(between("<fileChecksum>", "<"), sha1);
+        assert!(!xml.contains(&"0".repeat(40))); // the ibd checksum was filled in too
+    }
+
+    #[test]
+    fn crops_number_pixels_within_the_region() {
+        let crop = Crop::Millimetres { x: 0.011, y: -1.0, width: 0.02, height: 1.004 };
+        assert_eq!(crop.ranges(500.0, 100, 50).unwrap(), (5..16, 0..2)); // partly covered pixels are kept
+        let crop = Crop::Pixels { col: 90, row: 10, width: 20, height: 5 };
+        assert_eq!(crop.ranges(500.0, 100, 50).unwrap(), (90..100, 10..15));
+        assert!(Crop::Pixels { col: 100, row: 0, width: 5, height: 5 }.ranges(500.0, 100, 50).is_err());
+
+        let crop = Some(Crop::Pixels { col: 4, row: 7, width: 3, height: 2 });
+        let config = ImzmlConfig { crop, ..Default::default() };
+        let (mut maker, written) = start("crop", Default::default(), Default::default(), config);
+        let path = &written.0;
+        assert_eq!((maker.crop_cols.clone(), maker.crop_rows.clone()), (4..7, 7..9));
+        assert_eq!(maker.header.x_pixel_maximum, "3");
+        assert_eq!(maker.header.number_of_spectra, "6");
+        let mut span = PixelSpan::empty(3);
+        span.add_mz(50.0, 2);
+        maker.write_row(&mut PixelSpan::empty(3), 0).unwrap();
+        assert_eq!(maker.write_row(&mut span, 1).unwrap(), 1);
+        assert_eq!(maker.index, 6); // the empty row is written too
+        maker.write_end().unwrap();
+        let xml = std::fs::read_to_string(path.with_extension("imzml")).unwrap();
+        assert!(xml.contains(r#"name="position x" value="3""#) && xml.contains(r#"name="position y" value="2""#));
+        assert!(!xml.contains(r#"name="position x" value="4""#));
+    }
+
+    #[test]
+    fn empty_pixels_can_be_skipped_and_are_not_counted() {
+        let crop = Some(Crop::Pixels { col: 0, row: 0, width: 40, height: 30 });
+        let config = ImzmlConfig { crop, skip_empty: true, ..Default::default() };
+        let (mut maker, written) = start("sparse", Default::default(), Default::default(), config);
+        let path = &written.0;
+        let mut span = PixelSpan::empty(40);
+        span.add_mz(50.0, 7);
+        maker.write_row(&mut PixelSpan::empty(40), 0).unwrap();
+        maker.write_row(&mut span, 3).unwrap();
+        maker.write_end().unwrap();
+
+        let xml = std::fs::read_to_string(path.with_extension("imzml")).unwrap();
+        assert!(xml.contains(r#"<spectrumList count="1" "#) && xml.contains(r#"name="position x" value="8""#));
+        let mut reader = crate::imzml_reader::ImzmlReader::open(&path.with_extension("imzml")).unwrap();
+        reader.verify().unwrap(); // the padded header keeps the offsets and checksums right
+        assert_eq!((reader.spectra.len(), reader.spectra[0].row), (1, 3));
+    }
+
+    #[test]
+    fn modes_keep_the_events_their_spectra_hold() {
+        let mut cfg = image::Config { tof_pulse_length: 60_000_000, ..Default::default() };
+        cfg.calibration = mass::MassCalibration::Physical { t0: 1.0, k: 3.0 };
+        let processed = |tof| event_value(Mode::Processed, &cfg, tof, &[]);
+        assert_eq!(processed(1_000_000), None); // m/z 0
+        assert!((processed(31_000_000).unwrap() - 100.0).abs() < 1e-3);
+        assert_eq!(processed(53_000_000), None); // m/z 300.4
+        cfg.spectrum.tof_min = Some(10_000_000);
+        assert_eq!(event_value(Mode::Continuous, &cfg, 9_999_999, &[]), None);
+        assert_eq!(event_value(Mode::Continuous, &cfg, 59_000_000, &[]), Some(31360.0));
+        assert_eq!(event_value(Mode::Centroid, &cfg, 20_050_000, &[20_000_000]), Some(0.0));
+        assert_eq!(event_value(Mode::Centroid, &cfg, 19_850_000, &[20_000_000]), Some(0.0));
+        assert_eq!(event_value(Mode::Centroid, &cfg, 20_150_000, &[20_000_000]), None);
+    }
+
+    #[test]
+    fn centroid_spectra_count_the_events_of_each_peak() {
+        assert_eq!(nearest_peak(&[1000, 2000, 5000], 1400, 500), Some(0));
+        assert_eq!(nearest_peak(&[1000, 2000, 5000], 1600, 500), Some(1));
+        assert_eq!(nearest_peak(&[1000, 2000, 5000], 3000, 500), None);
+        assert_eq!(nearest_peak(&[1000, 2000, 5000], 5400, 500), Some(2));
+        assert_eq!(nearest_peak(&[], 5400, 500), None);
+        assert_eq!((nearest_peak(&[1000], 500, 500), nearest_peak(&[1000], 1500, 500)), (Some(0), None));
+
+        let config: image::Config = Default::default();
+        let peak = |apex| mass::Peak { apex, height: 10.0, fwhm: 1000, area: 50.0, snr: 10.0 };
+        let meta = image::Metadata { found_peaks: Some(vec![peak(20_000_000), peak(10_000_000)]), ..Default::default() };
+        let imzml_config = ImzmlConfig { mode: Mode::Centroid, ..Default::default() };
+        let (mut maker, written) = start("centroid", config, meta, imzml_config);
+        let path = &written.0;
+        assert_eq!(maker.peaks[0], (10_000_000, config.calibration.time_to_mass(10_000_000))); // by m/z
+        let mut pixel = Pixel::empty();
+        pixel.extend([1.0, 1.0, 1.0]); // peaks
+        maker.write_spectrum(&mut pixel, 0, 0).unwrap();
+        maker.write_end().unwrap();
+
+        let xml = std::fs::read_to_string(path.with_extension("imzml")).unwrap();
+        assert!(xml.contains(r#"name="centroid spectrum""#) && !xml.contains("profile spectrum"));
+        let mut reader = crate::imzml_reader::ImzmlReader::open(&path.with_extension("imzml")).unwrap();
+        assert_eq!(reader.mode, Mode::Centroid);
+        let spectrum = reader.spectra[0];
+        let mz = config.calibration.time_to_mass(20_000_000);
+        assert!((reader.mzs(&spectrum).unwrap()[0] - mz).abs() < 1e-4 * mz); // stored as f32
+        assert_eq!(reader.intensities(&spectrum).unwrap(), vec![3.0]);
+    }
+}
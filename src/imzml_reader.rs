@@ -0,0 +1,444 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    image,
+    imzml::{self, Crop, IntensityType, Mode, MzType},
+};
+
+/// where an array is stored in the .ibd file
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArrayRef {
+    pub len: usize, // the number of values
+    pub offset: u64,
+    pub encoded_len: u64, // the number of bytes, compressed or not
+}
+
+/// a spectrum of the .imzml file: its pixel (numbered from 0, unlike in the file) and its arrays
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpectrumRef {
+    pub col: usize,
+    pub row: usize,
+    pub offset: u64, // of its <spectrum> element in the .imzml file
+    pub mz: ArrayRef,
+    pub intensity: ArrayRef,
+}
+
+/// an imzML file read back with its .ibd file: the header and where every spectrum is stored
+pub struct ImzmlReader {
+    pub path: PathBuf,
+    pub uuid: String,
+    pub ibd_sha1: String,
+    pub mode: Mode,
+    pub mz_type: MzType,
+    pub intensity_type: IntensityType,
+    pub zlib: bool,
+    pub cols: usize, // max count of pixels x
+    pub rows: usize, // max count of pixels y
+    pub spectrum_count: usize, // as in the header
+    pub spectra: Vec<SpectrumRef>,
+    pub index: Option<Vec<u64>>, // the spectrum offsets of an indexedmzML file
+    pub file_checksum: Option<(u64, String)>, // the SHA-1 of an indexedmzML file and how many bytes it covers
+    ibd: BufReader<File>,
+}
+
+/// the value of an attribute in a line of xml
+fn attribute<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let pattern = format!(" {}=\"", name);
+    let start = line.find(&pattern)? + pattern.len();
+    line[start..].split('"').next()
+}
+
+/// the text of the first element of a line that starts with the tag, e.g., "12" for
+/// "<indexListOffset" in "<indexListOffset>12</indexListOffset>"
+fn element<'a>(line: &'a str, tag: &str) -> Option<&'a str> {
+    let start = line.find(tag)?;
+    let text = &line[start + line[start..].find('>')? + 1..];
+    text.split('<').next()
+}
+
+fn parse<T: std::str::FromStr>(value: Option<&str>, what: &str) -> Result<T, String> {
+    value.and_then(|v| v.trim().parse().ok()).ok_or_else(|| format!("the {} is not a number: {:?}", what, value))
+}
+
+impl ImzmlReader {
+    /// reads the header and the spectrum list of an .imzml file, line by line so that the
+    /// spectra of large images do not have to fit in memory as text; the .ibd file is next to it
+    pub fn open(path: &Path) -> Result<ImzmlReader, Box<dyn Error>> {
+        let mut xml = BufReader::new(File::open(path)?);
+        let (mut line, mut position) = (vec![], 0u64);
+        let (mut mode, mut uuid, mut ibd_sha1, mut mz_type, mut intensity_type) = (None, None, None, None, None);
+        let (mut zlib, mut centroid, mut cols, mut rows, mut spectrum_count) = (false, false, 0, 0, 0);
+        let (mut spectra, mut offsets, mut file_checksum) = (vec![], vec![], None);
+        let (mut index_offset, mut index_position) = (None, None); // where the index is said to be and is
+        let mut group = String::new(); // the referenceableParamGroup (or Ref in a spectrum) being read
+        let mut spectrum: Option<SpectrumRef> = None;
+        loop {
+            line.clear();
+            let read = xml.read_until(b'\n', &mut line)?;
+            if read == 0 {
+                break;
+            }
+            let text = String::from_utf8_lossy(&line);
+            // spectra are written back to back, so a line can end one and start the next
+            if text.contains("</spectrum>") {
+                spectra.push(spectrum.take().ok_or("</spectrum> without a <spectrum>")?);
+            }
+            if let Some(start) = text.find("<spectrum ") {
+                spectrum = Some(SpectrumRef { offset: position + start as u64, ..Default::default() });
+            }
+            if text.contains("<referenceableParamGroup ") || text.contains("<referenceableParamGroupRef ") {
+                group = attribute(&text, "id").or_else(|| attribute(&text, "ref")).unwrap_or("").to_string();
+            }
+            if text.contains("<spectrumList ") {
+                spectrum_count = parse(attribute(&text, "count"), "spectrum count")?;
+            }
+            if let Some(start) = text.find("<indexList ") {
+                index_position = Some(position + start as u64);
+            }
+            if text.contains("<offset ") {
+                offsets.push(parse(element(&text, "<offset "), "spectrum offset")?);
+            }
+            if text.contains("<indexListOffset>") {
+                index_offset = Some(parse::<u64>(element(&text, "<indexListOffset>"), "index offset")?);
+            }
+            if let Some(start) = text.find("<fileChecksum>") {
+                let checksum = element(&text, "<fileChecksum>").unwrap_or("").to_string();
+                file_checksum = Some((position + (start + "<fileChecksum>".len()) as u64, checksum));
+            }
+            position += read as u64;
+            let accession = match attribute(&text, "accession") {
+                Some(accession) => accession,
+                None => continue,
+            };
+            let value = attribute(&text, "value");
+            if let Some(spectrum) = spectrum.as_mut() {
+                let mut unused = ArrayRef::default(); // for the parameters of the spectrum itself
+                let array = match group.as_str() {
+                    "mzArray" => &mut spectrum.mz,
+                    "intensityArray" => &mut spectrum.intensity,
+                    _ => &mut unused,
+                };
+                match accession {
+                    "IMS:1000050" => spectrum.col = parse::<usize>(value, "position x")?.max(1) - 1,
+                    "IMS:1000051" => spectrum.row = parse::<usize>(value, "position y")?.max(1) - 1,
+                    "IMS:1000103" => array.len = parse(value, "external array length")?,
+                    "IMS:1000102" => array.offset = parse(value, "external offset")?,
+                    "IMS:1000104" => array.encoded_len = parse(value, "external encoded length")?,
+                    _ => (),
+                }
+                continue;
+            }
+            match (group.as_str(), accession) {
+                (_, "IMS:1000030") => mode = Some(Mode::Continuous),
+                (_, "IMS:1000031") => mode = Some(Mode::Processed),
+                (_, "MS:1000127") => centroid = true,
+                (_, "IMS:1000080") => uuid = value.map(|v| v.replace(['-', '{', '}'], "")),
+                (_, "IMS:1000091") => ibd_sha1 = value.map(str::to_string),
+                (_, "IMS:1000042") => cols = parse(value, "max count of pixels x")?,
+                (_, "IMS:1000043") => rows = parse(value, "max count of pixels y")?,
+                ("mzArray", "MS:1000574") | ("intensityArray", "MS:1000574") => zlib = true,
+                ("mzArray", accession) => mz_type = MzType::from_accession(accession).or(mz_type),
+                ("intensityArray", accession) => {
+                    intensity_type = IntensityType::from_accession(accession).or(intensity_type)
+                }
+                _ => (),
+            }
+        }
+        let missing = |what: &str| format!("{:?} is not an imzML file this converter reads: no {}", path, what);
+        if index_offset != index_position {
+            return Err(format!("{:?}: the index is at {:?}, not at {:?}", path, index_position, index_offset).into());
+        }
+        Ok(ImzmlReader {
+            path: path.to_path_buf(),
+            uuid: uuid.ok_or_else(|| missing("uuid"))?.to_lowercase(),
+            ibd_sha1: ibd_sha1.ok_or_else(|| missing("ibd SHA-1"))?.to_lowercase(),
+            mode: match mode.ok_or_else(|| missing("mode (processed or continuous)"))? {
+                Mode::Processed if centroid => Mode::Centroid,
+                mode => mode,
+            },
+            mz_type: mz_type.ok_or_else(|| missing("m/z array type"))?,
+            intensity_type: intensity_type.ok_or_else(|| missing("intensity array type"))?,
+            zlib,
+            cols,
+            rows,
+            spectrum_count,
+            spectra,
+            index: index_offset.map(|_| offsets),
+            file_checksum,
+            ibd: BufReader::new(File::open(path.with_extension("ibd"))?),
+        })
+    }
+
+    /// checks the .ibd file against the header (its uuid and SHA-1), that every spectrum is inside
+    /// the image and its arrays inside the .ibd file and, for an indexedmzML file, the offsets of
+    /// the spectra and the checksum of the file
+    pub fn verify(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut ibd = File::open(self.path.with_extension("ibd"))?;
+        let mut uuid = [0u8; 16];
+        ibd.read_exact(&mut uuid)?;
+        let uuid: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+        if uuid != self.uuid {
+            return Err(format!("the .ibd file has the uuid {} instead of {}", uuid, self.uuid).into());
+        }
+        let sha1 = imzml::file_to_sha1(&mut ibd)?;
+        if sha1 != self.ibd_sha1 {
+            return Err(format!("the SHA-1 of the .ibd file is {} instead of {}", sha1, self.ibd_sha1).into());
+        }
+        if self.spectra.len() != self.spectrum_count {
+            let (found, count) = (self.spectra.len(), self.spectrum_count);
+            return Err(format!("{} spectra instead of the {} in the header", found, count).into());
+        }
+        let ibd_len = ibd.metadata()?.len();
+        for (i, s) in self.spectra.iter().enumerate() {
+            if s.col >= self.cols || s.row >= self.rows {
+                return Err(format!("spectrum {} is outside the {}x{} pixels", i + 1, self.cols, self.rows).into());
+            }
+            if [s.mz, s.intensity].iter().any(|a| a.offset + a.encoded_len > ibd_len) {
+                return Err(format!("the arrays of spectrum {} are outside the .ibd file", i + 1).into());
+            }
+        }
+        if let Some(index) = &self.index {
+            if !index.iter().eq(self.spectra.iter().map(|s| &s.offset)) {
+                return Err("the index does not point at the spectra".into());
+            }
+        }
+        if let Some((covered, checksum)) = &self.file_checksum {
+            let mut sh = Sha1::default();
+            std::io::copy(&mut File::open(&self.path)?.take(*covered), &mut sh)?;
+            let sha1: String = sh.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+            if &sha1 != checksum {
+                return Err(format!("the SHA-1 of the .imzml file is {} instead of {}", sha1, checksum).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// the bytes of an array, inflated if compressed
+    fn read_array(&mut self, array: ArrayRef) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut bytes = vec![0; array.encoded_len as usize];
+        self.ibd.seek(SeekFrom::Start(array.offset))?;
+        self.ibd.read_exact(&mut bytes)?;
+        if self.zlib {
+            let mut inflated = vec![];
+            flate2::read::ZlibDecoder::new(&bytes[..]).read_to_end(&mut inflated)?;
+            bytes = inflated;
+        }
+        Ok(bytes)
+    }
+
+    pub fn mzs(&mut self, spectrum: &SpectrumRef) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mzs = self.mz_type.decode(&self.read_array(spectrum.mz)?);
+        match mzs.len() == spectrum.mz.len {
+            true => Ok(mzs),
+            false => Err(format!("{} m/z values instead of {}", mzs.len(), spectrum.mz.len).into()),
+        }
+    }
+
+    pub fn intensities(&mut self, spectrum: &SpectrumRef) -> Result<Vec<f64>, Box<dyn Error>> {
+        let intensities = self.intensity_type.decode(&self.read_array(spectrum.intensity)?);
+        match intensities.len() == spectrum.intensity.len {
+            true => Ok(intensities),
+            false => Err(format!("{} intensities instead of {}", intensities.len(), spectrum.intensity.len).into()),
+        }
+    }
+
+    /// the total ion count image and one ion image per m/z window (start inclusive and end
+    /// exclusive), of cols x rows pixels as in the header and following each other; continuous
+    /// spectra add the counts of a bin to the windows its m/z (center) falls in
+    pub fn images(&mut self, windows: &[(f64, f64)]) -> Result<(Vec<u32>, Vec<u32>), Box<dyn Error>> {
+        let page = self.cols * self.rows;
+        let (mut tic, mut ions) = (vec![0u32; page], vec![0u32; page * windows.len()]);
+        let mut last_mzs: Option<(ArrayRef, Vec<f64>)> = None; // continuous spectra share theirs
+        for i in 0..self.spectra.len() {
+            let spectrum = self.spectra[i];
+            let mzs = match last_mzs.take() {
+                Some((array, mzs)) if array == spectrum.mz => mzs,
+                _ => self.mzs(&spectrum)?,
+            };
+            let pixel = spectrum.row * self.cols + spectrum.col;
+            for (&mz, count) in mzs.iter().zip(self.intensities(&spectrum)?) {
+                let count = count as u32;
+                tic[pixel] = tic[pixel].saturating_add(count);
+                for (j, _) in windows.iter().enumerate().filter(|(_, &(low, high))| mz >= low && mz < high) {
+                    ions[j * page + pixel] = ions[j * page + pixel].saturating_add(count);
+                }
+            }
+            last_mzs = Some((spectrum.mz, mzs));
+        }
+        Ok((tic, ions))
+    }
+}
+
+/// how an image regenerated from an imzML file differs from the one made from the events
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comparison {
+    pub name: String,
+    pub expected: u64, // the total counts of the image made from the events
+    pub found: u64,    // of the image regenerated from the imzML file
+    pub differing_pixels: usize,
+    pub max_difference: u32,
+}
+
+impl Comparison {
+    pub fn new(name: &str, expected: &[u32], found: &[u32]) -> Comparison {
+        let differences = || expected.iter().zip(found).map(|(&e, &f)| e.abs_diff(f));
+        Comparison {
+            name: name.to_string(),
+            expected: expected.iter().map(|&c| c as u64).sum(),
+            found: found.iter().map(|&c| c as u64).sum(),
+            differing_pixels: differences().filter(|&d| d > 0).count(),
+            max_difference: differences().max().unwrap_or(0),
+        }
+    }
+
+    pub fn matches(&self) -> bool { self.differing_pixels == 0 }
+}
+
+/// verifies the imzML file of an image and compares its total ion count image and the ion images
+/// of the time of flight windows (named by `names`) regenerated from it with those made from the
+/// events the file holds (see `imzml::event_value`, e.g., those below m/z 300 for processed files,
+/// with the found peaks of the image for centroid files) by `Image::to_buffer_where` and
+/// `Image::windows_to_buffers_where`, over the crop it was written with; windows with an end the
+/// file does not hold (or, for centroid files, that split a peak) are skipped. The windows become
+/// m/z windows under the calibration of the image, so events at their edges can land on the other
+/// side after the rounding of processed m/z values or with the bin they are counted in
+pub fn validate(
+    image: &image::Image, crop: Option<Crop>, windows: &[(i64, i64)], names: &[String],
+) -> Result<Vec<Comparison>, Box<dyn Error>> {
+    let mut reader = ImzmlReader::open(&image.tpx3_path.with_extension("imzml"))?;
+    reader.verify()?;
+    let cfg = &image.config;
+    let (cols, rows) = (cfg.cols() as usize, cfg.rows() as usize);
+    let (crop_cols, crop_rows) = match crop {
+        Some(crop) => crop.ranges(cfg.pixels_per_mm, cols, rows)?,
+        None => (0..cols, 0..rows),
+    };
+    if (crop_cols.len(), crop_rows.len()) != (reader.cols, reader.rows) {
+        let (c, r) = (crop_cols.len(), crop_rows.len());
+        return Err(format!("the imzML file has {}x{} pixels, the image {}x{}", reader.cols, reader.rows, c, r).into());
+    }
+    let mut apexes: Vec<i64> = image.meta.found_peaks.iter().flatten().map(|p| p.apex).collect();
+    apexes.sort_unstable();
+    let mode = reader.mode;
+    let kept = |tof: i64| imzml::event_value(mode, cfg, tof, &apexes).is_some();
+    // centroid spectra count the events of a peak at its m/z, so a window has to hold whole peaks
+    let ptw = cfg.peak_time_window;
+    let whole_peaks = |start: i64, end: i64| {
+        let inside = |a: i64| a - ptw >= start && a + ptw <= end;
+        let outside = |a: i64| a + ptw <= start || a - ptw >= end;
+        mode != Mode::Centroid || apexes.iter().all(|&a| inside(a) || outside(a))
+    };
+    let (mut held, mut held_names) = (vec![], vec![]);
+    for (&(start, end), name) in windows.iter().zip(names) {
+        if !(kept(start) && kept(end - 1)) {
+            println!("{}: outside of what the imzML file holds, not compared", name);
+        } else if !whole_peaks(start, end) {
+            println!("{}: splits a peak of the centroid spectra, not compared", name);
+        } else {
+            held.push((start, end));
+            held_names.push(name);
+        }
+    }
+    let mz_windows: Vec<(f64, f64)> = held
+        .iter()
+        .map(|&(start, end)| (cfg.calibration.time_to_mass(start), cfg.calibration.time_to_mass(end)))
+        .collect();
+    let (tic, ions) = reader.images(&mz_windows)?;
+    let cropped = |buffer: &[u32]| -> Vec<u32> {
+        crop_rows.clone().flat_map(|r| buffer[r * cols + crop_cols.start..r * cols + crop_cols.end].to_vec()).collect()
+    };
+    let mut comparisons = vec![Comparison::new("TIC", &cropped(&image.to_buffer_where::<u32>(kept)?), &tic)];
+    let buffers = image.windows_to_buffers_where::<u32>(&held, kept)?;
+    let pages = buffers.chunks(cols * rows).zip(ions.chunks(reader.cols * reader.rows));
+    for (name, (expected, found)) in held_names.into_iter().zip(pages) {
+        comparisons.push(Comparison::new(name, &cropped(expected), found));
+    }
+    Ok(comparisons)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        imzml::{IMZMLMaker, ImzmlConfig},
+        mass,
+        pixel::Pixel,
+    };
+
+    fn write(
+        name: &str, config: ImzmlConfig, image_config: image::Config, pixels: &[(usize, usize, &[f32])],
+    ) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("two_grids_read_{}_{}.tpx3c", name, std::process::id()));
+        let image = image::Image { tpx3_path: path.clone(), config: image_config, meta: Default::default() };
+        let mut maker = IMZMLMaker::new(image, config).unwrap();
+        maker.write_start().unwrap();
+        for &(col, row, values) in pixels {
+            let mut pixel = Pixel::empty();
+            pixel.extend(values.iter().copied());
+            maker.write_spectrum(&mut pixel, col, row).unwrap();
+        }
+        maker.write_end().unwrap();
+        path
+    }
+
+    fn remove(path: &Path) {
+        std::fs::remove_file(path.with_extension("ibd")).unwrap();
+        std::fs::remove_file(path.with_extension("imzml")).unwrap();
+    }
+
+    #[test]
+    fn processed_files_read_back_and_verify() {
+        let crop = Some(Crop::Pixels { col: 0, row: 0, width: 2, height: 1 });
+        let config = ImzmlConfig { zlib: true, crop, ..Default::default() };
+        let path = write("processed", config, Default::default(), &[(0, 0, &[50.0, 60.0, 50.0]), (1, 0, &[])]);
+        let mut reader = ImzmlReader::open(&path.with_extension("imzml")).unwrap();
+        assert_eq!((reader.mode, reader.zlib, reader.cols, reader.rows), (Mode::Processed, true, 2, 1));
+        assert_eq!(reader.spectra.len(), 2);
+        reader.verify().unwrap();
+        let first = reader.spectra[0];
+        assert_eq!(reader.mzs(&first).unwrap(), vec![50.0, 60.0]);
+        assert_eq!(reader.intensities(&first).unwrap(), vec![2.0, 1.0]);
+        let (tic, ions) = reader.images(&[(49.0, 51.0), (59.0, 70.0), (100.0, 200.0)]).unwrap();
+        assert_eq!((tic, ions), (vec![3, 0], vec![2, 0, 1, 0, 0, 0]));
+
+        let mut ibd = std::fs::read(path.with_extension("ibd")).unwrap();
+        *ibd.last_mut().unwrap() ^= 1;
+        std::fs::write(path.with_extension("ibd"), ibd).unwrap();
+        assert!(reader.verify().unwrap_err().to_string().contains("SHA-1 of the .ibd file"));
+        remove(&path);
+    }
+
+    #[test]
+    fn continuous_files_share_their_mz_array_when_read_back() {
+        let mut image_config =
+            image::Config { width: 0.1, height: 0.1, tof_pulse_length: 50_000_000, ..Default::default() };
+        let binning = mass::Binning::Time { width: 1_000_000.0 };
+        image_config.spectrum = mass::SpectrumConfig { binning, tof_min: None, tof_max: Some(10_000_000) };
+        let crop = Some(Crop::Pixels { col: 0, row: 0, width: 1, height: 2 });
+        let config = ImzmlConfig { mode: Mode::Continuous, indexed: false, crop, ..Default::default() };
+        let path = write("continuous", config, image_config, &[(0, 0, &[2.0, 7.0, 2.0]), (0, 1, &[9.0])]);
+        let mut reader = ImzmlReader::open(&path.with_extension("imzml")).unwrap();
+        assert_eq!((reader.mode, reader.index.is_some()), (Mode::Continuous, false));
+        reader.verify().unwrap();
+        assert_eq!(reader.spectra[0].mz, reader.spectra[1].mz);
+        let mzs = reader.mzs(&reader.spectra[0].clone()).unwrap();
+        let (tic, ions) = reader.images(&[(mzs[2] - 1e-3, mzs[2] + 1e-3)]).unwrap();
+        assert_eq!((tic, ions), (vec![3, 1], vec![2, 0]));
+        remove(&path);
+    }
+
+    #[test]
+    fn comparisons_count_differing_pixels() {
+        let comparison = Comparison::new("TIC", &[1, 5, 0], &[1, 3, 1]);
+        assert_eq!((comparison.expected, comparison.found), (6, 5));
+        assert_eq!((comparison.differing_pixels, comparison.max_difference), (2, 2));
+        assert!(!comparison.matches() && Comparison::new("TIC", &[2], &[2]).matches());
+    }
+}
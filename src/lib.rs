@@ -0,0 +1,26 @@
+#![allow(dead_code)]
+// #![allow(unused_imports)]
+#![allow(unused_must_use)]
+#![allow(unused_mut)]
+#![allow(unused_variables)]
+// #![allow(unused_parens)]
+#![allow(stable_features)]
+#![feature(unchecked_math)]
+
+pub mod config;
+pub mod datacube;
+pub mod hit;
+pub mod image;
+pub mod imzml;
+pub mod imzml_reader;
+pub mod mass;
+pub mod math;
+pub mod ometiff;
+pub mod pipeline;
+pub mod pixel;
+pub mod pulse;
+pub mod reader;
+pub mod render;
+pub mod roi;
+pub mod stage;
+pub mod writer;
@@ -1,29 +1,16 @@
-#![allow(dead_code)]
-// #![allow(unused_imports)]
-#![allow(unused_must_use)]
-#![allow(unused_mut)]
-#![allow(unused_variables)]
-// #![allow(unused_parens)]
-#![feature(unchecked_math)]
-
-
 use std::error::Error;
 
-use rayon::prelude::*;
-
-mod hit;
-mod image;
-mod imzml;
-mod mass;
-mod math;
-mod pixel;
-mod pulse;
-mod reader;
-mod stage;
-mod writer;
+use two_grids_script::{config, image, imzml, pipeline};
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let now = std::time::Instant::now();
+    // "--imzml processed", "continuous" or "centroid" also converts the files to imzML, "validate"
+    // instead checks the imzML files of earlier runs against the events
+    let args: Vec<String> = std::env::args().collect();
+    let validate = args.iter().any(|a| a == "validate");
+    let imzml_mode: Option<imzml::Mode> = match args.iter().position(|a| a == "--imzml") {
+        Some(i) => Some(args.get(i + 1).ok_or("--imzml needs a mode: processed, continuous or centroid")?.parse()?),
+        None => None,
+    };
     let current_dir = std::env::current_dir()?;
     for entry in std::fs::read_dir(current_dir)?.filter_map(Result::ok) {
         let path = entry.path();
@@ -32,16 +19,15 @@ fn main() -> Result<(), Box<dyn Error>> {
         // // For conversion of .tpx3 files to .tpx3c files
         // if path.extension() == Some(&std::ffi::OsString::from("tpx3")) {
         //     let now = std::time::Instant::now();
-        //     writer::centroid_cluster_compress(&path)?;
+        //     two_grids_script::writer::centroid_cluster_compress(&path)?;
         //     println!("centroiding took {} ms", now.elapsed().as_millis());
         // }
 
-        // For quick plotting of the mass spectrum
-        if path.extension() == Some(&std::ffi::OsString::from("tpx3c")) {
-            let now = std::time::Instant::now();
-            writer::plotly_spectra(&path, Some(tof_pulse_length));
-            println!("plotly took {} ms", now.elapsed().as_millis());
-        }
+        // // For migration of .tpx3c files written with version 1 blob packets (to *_v2.tpx3c)
+        // if path.extension() == Some(&std::ffi::OsString::from("tpx3c")) {
+        //     two_grids_script::writer::migrate_tpx3c(&path)?;
+        // }
+
         let now = std::time::Instant::now();
         if path.extension() == Some(&std::ffi::OsString::from("tpx3c")) {
             // setup configuration options for the image
@@ -58,54 +44,27 @@ fn main() -> Result<(), Box<dyn Error>> {
             };
 
             config.update();
-            // make the image structure
-            let mut image_data = image::Image {
-                tpx3_path: path.clone(),
-                config,
-                meta: image::Metadata { ..Default::default() },
+            // a run_config.json in the current directory (e.g., a *_run_config.json written by an
+            // earlier run) replaces the configuration above, and can hold calibrants
+            let run_config_path = std::path::Path::new("run_config.json");
+            let mut run_config = match run_config_path.exists() {
+                true => config::RunConfig::load(run_config_path)?,
+                false => config::RunConfig { image: config, ..Default::default() },
             };
-
-            image_data.auto_generate_coordinates().unwrap();
-            image_data.auto_generate_dead_pixels().unwrap();
-            image_data.auto_generate_mass_list()?.unwrap();
-
-            let base_name = path.file_stem().unwrap().to_str().unwrap();
-            let fname = path.with_file_name(base_name.to_owned() + &format!("_tic.png"));
-            let buffer = image_data.to_buffer().unwrap();
-            // save total ion count image
-            writer::save_png(&buffer, config.cols(), config.rows(), &fname);
-            let masses = image_data.meta.found_peaks.take().unwrap();
-            let masses: Vec<Vec<i64>> = masses.chunks(6).map(|a| a.to_vec()).collect();
-            masses.par_iter().for_each(|peak_times|
-                {
-                    let now = std::time::Instant::now();
-                    let mass_image = image::Image {
-                        tpx3_path: path.clone(),
-                        config: image::Config {
-                            peak_time: None,
-                            peak_time_window: 150_000, // +/- 150 ns
-                            ..config
-                        },
-                        meta: image::Metadata {
-                            coordinates: image_data.meta.coordinates.clone(),
-                            dead_pixels: image_data.meta.dead_pixels.clone(),
-                            ..Default::default()
-                        },
-                    };
-                    let buffers = mass_image.times_to_buffers(peak_times).unwrap();
-                    for (i, pt) in peak_times.iter().enumerate() {
-                        let mz = mass::time_to_mass(*pt);
-                        let fname = path.with_file_name(base_name.to_owned() + &format!("_{:.1$}mz.png", mz, 1));
-                        let page = (config.cols() * config.rows()) as usize;
-                        let (start, end) = (page * i, page * (i+1));
-                        writer::save_png(&buffers[start..end], config.cols(), config.rows(), &fname);
-                    }
+            if let Some(mode) = imzml_mode {
+                run_config.imzml.get_or_insert_with(Default::default).mode = mode;
+            }
+            if validate {
+                // with the run config the imzML file was written with, if it is there
+                let stem = path.file_stem().unwrap().to_str().unwrap();
+                let used = path.with_file_name(format!("{}_run_config.json", stem));
+                if path.with_extension("imzml").exists() {
+                    let run_config = if used.exists() { config::RunConfig::load(&used)? } else { run_config };
+                    pipeline::validate(&path, &run_config)?;
                 }
-            );
-            // to generate an imzml file, uncomment the next two commented lines:
-
-            // let mut imzml_data = imzml::IMZMLMaker::new(image_data)?;
-            // imzml_data.stream_convert_and_save();
+                continue;
+            }
+            pipeline::run(&path, &run_config)?;
             println!("processing took {} s", now.elapsed().as_secs());
         }
 
@@ -1,96 +1,858 @@
 use crate::{math, reader};
-use std::{collections::HashMap, error::Error};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
 
-const TIME_BIN_WIDTH: i64 = 1563; // ps to bins (decimal loss from 1.5625, but is hash)
+/// in ps, the time of arrival resolution of hits (25 ns / 16)
+pub const TIME_BIN_WIDTH: f64 = 1562.5;
+/// in ps, times of flight beyond this are dropped when neither a pulse length nor a range is set
+const MAX_TOF: i64 = 1_000_000_000;
+
+/// how the spectrum is binned: uniformly in time of flight (ps, e.g., the 1562.5 ps of a hit or
+/// the ~260 ps of a TDC) or uniformly in m/z under the mass calibration
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Binning {
+    Time { width: f64 },
+    Mass { width: f64 },
+}
+
+/// binning and range of spectra; the range limits are times of flight in ps
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpectrumConfig {
+    pub binning: Binning,
+    pub tof_min: Option<i64>,
+    pub tof_max: Option<i64>,
+}
+
+impl Default for SpectrumConfig {
+    fn default() -> SpectrumConfig {
+        SpectrumConfig { binning: Binning::Time { width: TIME_BIN_WIDTH }, tof_min: None, tof_max: None }
+    }
+}
+
+impl SpectrumConfig {
+    /// checks that the bins have a (finite) positive width, e.g., after reading a run config
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let width = match self.binning {
+            Binning::Time { width } | Binning::Mass { width } => width,
+        };
+        if !(width.is_finite() && width > 0.0) {
+            return Err(format!("the spectrum bin width must be positive, not {}", width).into());
+        }
+        Ok(())
+    }
+
+    /// the lower edge of the first bin, in ps or m/z
+    fn start(&self) -> f64 {
+        match self.binning {
+            Binning::Time { .. } => self.tof_min.unwrap_or(0) as f64,
+            Binning::Mass { .. } => 0.0,
+        }
+    }
+
+    /// the end of the time of flight range (ps)
+    pub(crate) fn tof_max(&self, tof_pulse_length: Option<i64>) -> i64 {
+        self.tof_max.or(tof_pulse_length).unwrap_or(MAX_TOF)
+    }
+
+    /// the number of bins up to the end of the time of flight range (for increasing calibrations)
+    pub(crate) fn bins(&self, tof_max: i64, calibration: &MassCalibration) -> usize {
+        self.index(tof_max - 1, tof_max, calibration).map_or(0, |i| i + 1)
+    }
+
+    /// the bin a time of flight (ps) falls in, if it is within the range
+    pub(crate) fn index(&self, tof: i64, tof_max: i64, calibration: &MassCalibration) -> Option<usize> {
+        if tof < self.tof_min.unwrap_or(0) || tof >= tof_max {
+            return None;
+        }
+        let (position, width) = match self.binning {
+            Binning::Time { width } => (tof as f64 + 0.5, width), // hit times are floored to whole ps
+            Binning::Mass { width } => (calibration.time_to_mass(tof), width),
+        };
+        let bin = (position - self.start()) / width;
+        (bin >= 0.0).then_some(bin as usize)
+    }
+}
+
+/// a dense histogram of times of flight (or m/z), starting at the lower edge `start`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spectrum {
+    pub binning: Binning,
+    pub start: f64, // in ps or m/z
+    pub counts: Vec<u32>,
+}
+
+impl Spectrum {
+    /// an empty spectrum, bins are added as counts arrive
+    pub fn new(config: &SpectrumConfig) -> Spectrum {
+        Spectrum { binning: config.binning, start: config.start(), counts: vec![] }
+    }
+
+    pub(crate) fn increment(&mut self, index: usize) {
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+    }
+
+    /// adds the counts of another spectrum with the same binning
+    pub fn merge(mut self, other: Spectrum) -> Spectrum {
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        self.counts.iter_mut().zip(other.counts).for_each(|(c, o)| *c += o);
+        self
+    }
+
+    pub fn width(&self) -> f64 {
+        match self.binning {
+            Binning::Time { width } | Binning::Mass { width } => width,
+        }
+    }
+
+    /// the lower edge of every bin, in ps or m/z
+    pub fn axis(&self) -> Vec<f64> {
+        (0..self.counts.len()).map(|i| self.start + i as f64 * self.width()).collect()
+    }
+
+    /// the bins with counts and their (zero) neighbours, for compact plots and csv files
+    pub fn zero_padded(&self) -> (Vec<f64>, Vec<u32>) {
+        let n = self.counts.len();
+        let occupied = |i: usize| self.counts[i.saturating_sub(1)..(i + 2).min(n)].iter().any(|&c| c > 0);
+        (0..n).filter(|&i| occupied(i)).map(|i| (self.start + i as f64 * self.width(), self.counts[i])).unzip()
+    }
+}
 
 /// Takes a tpx3 or tpx3c file path and a pulse length to produce a spectrum
 /// The tof_pulse_length is the length of the 'true' tof cycle (in ps)
 /// TODO: if we decrease the processing cost of TDCs (and then do not need to
 /// double or multi-up the pulses for each TDC) then we can simplify this
 /// by removing the pulse length parameter
-/// We might add a time correction / calibration factor here
 /// const TOF_PULSE_LENGTH: i64 = 94_554_700; // 1000 m/z
 /// const TOF_PULSE_LENGTH: i64 = 70_033_985; // 500 m/z
 /// const TOF_PULSE_LENGTH: i64 = 56_687_500; // 350 m/z
 /// const TOF_PULSE_LENGTH: i64 = 48_276_175; // 200 m/z
+/// Pulses are accumulated in parallel; the calibration is only used for m/z binning
+/// Hits are filtered like in the images (`Hit::is_counted`), pass no dead pixels to keep them all
 pub fn spectrum(
-    tpx3_path: &std::path::Path, tof_pulse_length: Option<i64>,
-) -> Result<(Vec<i64>, Vec<u32>), Box<dyn Error>> {
-    let data = reader::TPX3Reader::new(tpx3_path)?;
-    let mut map: HashMap<i64, u32, nohash_hasher::BuildNoHashHasher<i64>> =
-        (0..1).map(|i| (i as i64, i as u32)).collect();
+    tpx3_path: &std::path::Path, tof_pulse_length: Option<i64>, config: &SpectrumConfig,
+    calibration: &MassCalibration, dead_pixels: &[u16],
+) -> Result<Spectrum, Box<dyn Error>> {
+    config.validate()?;
+    let tof_max = config.tof_max(tof_pulse_length);
     let now = std::time::Instant::now();
-    for pulse in reader::TPX3Reader::new(tpx3_path)? {
-        for hit in pulse.hits.iter() {
-            let tof = (hit.toa - pulse.time) % tof_pulse_length.unwrap_or(i64::MAX);
-            if tof < 0 { // remove any negative TOF values due to TPX3 firmware issue
-                continue;
+    let spectrum = reader::TPX3Reader::new(tpx3_path)?
+        .par_bridge()
+        .fold(|| Spectrum::new(config), |mut spectrum, pulse| {
+            for hit in pulse.hits.iter().filter(|h| h.is_counted(dead_pixels)) {
+                let tof = (hit.toa - pulse.time) % tof_pulse_length.unwrap_or(i64::MAX);
+                // negative TOF values (due to a TPX3 firmware issue) are out of range
+                if let Some(index) = config.index(tof, tof_max, calibration) {
+                    spectrum.increment(index);
+                }
+            }
+            spectrum
+        })
+        .reduce(|| Spectrum::new(config), Spectrum::merge);
+    println!("building spectrum took {} ms", now.elapsed().as_millis());
+    Ok(spectrum)
+}
+
+/// smoothing applied to the spectrum before looking for maxima; widths are in bins
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Smoothing {
+    MovingAverage { window: usize },
+    SavitzkyGolay { window: usize, order: usize },
+    Gaussian { sigma: f64 },
+}
+
+impl Smoothing {
+    /// the (odd length, centered) convolution kernel
+    fn kernel(&self) -> Vec<f64> {
+        match *self {
+            Smoothing::MovingAverage { window } => {
+                let half = window / 2;
+                vec![1.0 / (2 * half + 1) as f64; 2 * half + 1]
+            }
+            Smoothing::SavitzkyGolay { window, order } => {
+                let half = (window / 2).max(order.div_ceil(2)) as i64;
+                let rows: Vec<Vec<f64>> =
+                    (-half..=half).map(|j| (0..=order).map(|p| (j as f64).powi(p as i32)).collect()).collect();
+                // the weight of each point is the fitted value at the center for a unit impulse there
+                (0..rows.len())
+                    .map(|k| {
+                        let impulse: Vec<f64> = (0..rows.len()).map(|i| (i == k) as u8 as f64).collect();
+                        math::least_squares(&rows, &impulse).map_or(0.0, |c| c[0])
+                    })
+                    .collect()
+            }
+            Smoothing::Gaussian { sigma } => {
+                let half = (3.0 * sigma).ceil().max(0.0) as i64;
+                let weights: Vec<f64> = (-half..=half).map(|j| (-0.5 * (j as f64 / sigma).powi(2)).exp()).collect();
+                let total: f64 = weights.iter().sum();
+                weights.iter().map(|w| w / total).collect()
             }
-            let index: i64 = (tof / TIME_BIN_WIDTH) * TIME_BIN_WIDTH;
-            let count = map.entry(index).or_insert(0);
-            *count += 1;
         }
     }
-    println!("building hashmap took {} ms", now.elapsed().as_millis());
-    // now that we've extracted the data, sort it to spectrum based on time
-    let now = std::time::Instant::now();
-    let mut pairs: Vec<(i64, u32)> = map.iter().map(|(a, b)| (*a, *b)).collect();
-    pairs.sort_by(|a, b| a.0.cmp(&b.0));
-    println!("sorting hashmap took {} ms", now.elapsed().as_millis());
-    let (mut times, mut intensities) = (vec![], vec![]);
-    for (time_index, intensity) in pairs.iter() {
-        times.push(*time_index);
-        intensities.push(*intensity);
-    }
-    Ok((times, intensities))
-}
-
-/// adds zeros to starts and ends of peaks to allow for easy plotting of mass spectra
-pub fn zero_pad(times: &[i64], intensities: &[u32]) -> (Vec<i64>, Vec<u32>) {
-    let mut prev_time: i64 = *times.first().unwrap();
-    let (mut pad_time, mut pad_intensity) = (vec![prev_time - TIME_BIN_WIDTH], vec![0]);
-    for (&time, &intensity) in times.iter().zip(intensities) {
-        if time - prev_time != TIME_BIN_WIDTH {
-            pad_time.push(prev_time + TIME_BIN_WIDTH);
-            pad_time.push(time - TIME_BIN_WIDTH);
-            pad_intensity.push(0);
-            pad_intensity.push(0);
-        }
-        pad_time.push(time);
-        pad_intensity.push(intensity);
-        prev_time = time;
-    }
-    pad_time.push(pad_time.last().unwrap() + TIME_BIN_WIDTH);
-    pad_intensity.push(0);
-    (pad_time, pad_intensity)
-}
-
-pub fn find_peaks(chromatogram: &[u32]) -> Vec<usize> {
-    let diff: Vec<f64> = chromatogram.windows(2).map(|a| a[1] as f64 - a[0] as f64).collect();
-    let wind = 15;
-    let smooth = math::smooth(&diff, wind);
-    let smooth = math::smooth(&smooth, wind);
+
+    /// convolves with the kernel, repeating the edge values so the output has the input's length
+    pub fn apply(&self, values: &[f64]) -> Vec<f64> {
+        let kernel = self.kernel();
+        let (half, last) = ((kernel.len() / 2) as i64, values.len() as i64 - 1);
+        (0..=last)
+            .map(|i| {
+                kernel.iter().enumerate().map(|(k, w)| w * values[(i + k as i64 - half).clamp(0, last) as usize]).sum()
+            })
+            .collect()
+    }
+}
+
+/// parameters of `find_peaks`
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeakPicking {
+    pub smoothing: Smoothing,
+    pub min_snr: f64,          // smoothed height above the local baseline, in local noise units
+    pub min_height: f64,       // in counts per bin above the local baseline
+    pub noise_window: usize,   // in bins, the spectrum is split in segments this long for the noise estimate
+    pub min_separation: i64,   // in ps, of apexes; the lower of two closer peaks is dropped
+}
+
+impl Default for PeakPicking {
+    fn default() -> PeakPicking {
+        PeakPicking {
+            smoothing: Smoothing::MovingAverage { window: 15 },
+            min_snr: 10.0,
+            min_height: 0.0,
+            noise_window: 1000, // ~1.6 us
+            min_separation: 20_000, // 20 ns
+        }
+    }
+}
+
+impl PeakPicking {
+    /// checks that a gaussian smoothing has a (finite) positive width, e.g., after reading a run config
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        match self.smoothing {
+            Smoothing::Gaussian { sigma } if !(sigma.is_finite() && sigma > 0.0) => {
+                Err(format!("the gaussian smoothing sigma must be positive, not {}", sigma).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// a picked peak; times are in ps and intensities in counts per bin
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Peak {
+    pub apex: i64,   // interpolated maximum of the smoothed spectrum
+    pub height: f64, // smoothed, above the baseline
+    pub fwhm: i64,
+    pub area: f64,   // counts above the baseline between the surrounding minima
+    pub snr: f64,
+}
+
+/// median and robust standard deviation (from the median absolute deviation) of a segment;
+/// counting noise (the square root of the median, but at least one count) is the lower bound
+fn noise_estimate(segment: &[f64]) -> (f64, f64) {
+    let median = |v: &mut Vec<f64>| {
+        v.sort_by(|a, b| a.total_cmp(b));
+        v[v.len() / 2]
+    };
+    let baseline = median(&mut segment.to_vec());
+    let mad = median(&mut segment.iter().map(|v| (v - baseline).abs()).collect());
+    (baseline, (1.4826 * mad).max(baseline.sqrt()).max(1.0))
+}
+
+/// picks peaks from a time binned spectrum: local maxima of the smoothed spectrum that stand out
+/// of the local noise are kept
+pub fn find_peaks(spectrum: &Spectrum, picking: &PeakPicking) -> Vec<Peak> {
+    if spectrum.counts.is_empty() {
+        return vec![];
+    }
+    let (first, width) = (spectrum.start, spectrum.width());
+    let raw: Vec<f64> = spectrum.counts.iter().map(|&c| c as f64).collect();
+    let smooth = picking.smoothing.apply(&raw);
+    let noise: Vec<(f64, f64)> = raw.chunks(picking.noise_window.max(1)).map(noise_estimate).collect();
+    let n = smooth.len();
     let mut peaks = vec![];
-    for i in 0..(smooth.len() - 1) {
-        let this = smooth[i];
-        let next = smooth[i + 1];
-        if this > 0.0
-            && next < 0.0
-            && i > wind + 3
-            && this - next >= 0.7
-            && chromatogram[i + wind + 7] as f64 > 5000.0
-        {
-            peaks.push(i + math::argmax_u32(&chromatogram[i..i + 2 * wind]).0);
+    for i in 0..n {
+        let (left, right) = (smooth[i.saturating_sub(1)], smooth[(i + 1).min(n - 1)]);
+        if (i > 0 && smooth[i] <= left) || (i + 1 < n && smooth[i] < right) {
+            continue;
+        }
+        let (baseline, sigma) = noise[i / picking.noise_window.max(1)];
+        let height = smooth[i] - baseline;
+        if height / sigma < picking.min_snr || height < picking.min_height || height <= 0.0 {
+            continue;
+        }
+        // interpolated crossings of the half maximum
+        let half = baseline + height / 2.0;
+        let mut lo = i;
+        while lo > 0 && smooth[lo - 1] > half {
+            lo -= 1;
+        }
+        let mut hi = i;
+        while hi + 1 < n && smooth[hi + 1] > half {
+            hi += 1;
+        }
+        let crossing = |inside: usize, outside: usize| {
+            let (a, b) = (smooth[inside], smooth[outside]);
+            inside as f64 + (outside as f64 - inside as f64) * (a - half) / (a - b)
+        };
+        let lo_half = if lo > 0 { crossing(lo, lo - 1) } else { 0.0 };
+        let hi_half = if hi + 1 < n { crossing(hi, hi + 1) } else { (n - 1) as f64 };
+        // the area extends down to the baseline or the valley towards a neighbouring peak
+        while lo > 0 && smooth[lo - 1] < smooth[lo] && smooth[lo - 1] > baseline {
+            lo -= 1;
+        }
+        while hi + 1 < n && smooth[hi + 1] < smooth[hi] && smooth[hi + 1] > baseline {
+            hi += 1;
+        }
+        let area = raw[lo..=hi].iter().map(|v| v - baseline).sum();
+        // parabolic interpolation of the apex
+        let curvature = left - 2.0 * smooth[i] + right;
+        let offset = if i > 0 && i + 1 < n && curvature < 0.0 { 0.5 * (left - right) / curvature } else { 0.0 };
+        peaks.push(Peak {
+            apex: (first + (i as f64 + offset) * width).round() as i64,
+            height,
+            fwhm: ((hi_half - lo_half) * width).round() as i64,
+            area,
+            snr: height / sigma,
+        });
+    }
+    // enforce the minimum separation, keeping the highest peaks
+    peaks.sort_by(|a, b| b.height.total_cmp(&a.height));
+    let mut kept: Vec<Peak> = vec![];
+    for peak in peaks {
+        if kept.iter().all(|k| (k.apex - peak.apex).abs() >= picking.min_separation) {
+            kept.push(peak);
+        }
+    }
+    kept.sort_by_key(|p| p.apex);
+    kept
+}
+
+/// a row of the peak table: a found peak with its calibrated mass and the size of its ion image
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PeakSummary {
+    pub tof: i64, // apex, in ps
+    pub mz: f64,
+    pub fwhm_ns: f64,
+    pub fwhm_da: f64,
+    pub resolving_power: f64, // m / fwhm in m/z, 0 for a peak narrower than a bin (fwhm 0)
+    pub area: f64,            // in counts
+    pub snr: f64,
+    pub pixels: usize, // image pixels with at least one count in the peak window
+}
+
+impl PeakSummary {
+    pub fn new(peak: &Peak, calibration: &MassCalibration, pixels: usize) -> PeakSummary {
+        let mz = calibration.time_to_mass(peak.apex);
+        let fwhm_da = (calibration.time_to_mass(peak.apex + peak.fwhm / 2)
+            - calibration.time_to_mass(peak.apex - peak.fwhm / 2))
+            .abs();
+        PeakSummary {
+            tof: peak.apex,
+            mz,
+            fwhm_ns: peak.fwhm as f64 / 1000.0,
+            fwhm_da,
+            resolving_power: if fwhm_da > 0.0 { mz / fwhm_da } else { 0.0 },
+            area: peak.area,
+            snr: peak.snr,
+            pixels,
         }
     }
-    peaks
 }
 
 
-/// this equation is simply a quick conversion function and does not represent a calibrated mass function
-pub fn time_to_mass(time: i64) -> f64 {
-    // 0.0461 * ((time as f64) / 1_000_000.0).powf(2.1782)
-    let x = time as f64 / 1_000_000.0;
-    0.139 * x.powf(2.0) - 1.413 * x + 3.686
-} // HOT FUNCTION -> WORK TO OPTIMIZE!!!
-// y2=0.139*x.^2-1.413*x+3.686
\ No newline at end of file
+/// the highest polynomial degree (plus one) a `MassCalibration::Polynomial` can hold
+pub const MAX_COEFFICIENTS: usize = 5;
+
+/// a known (time-of-flight, m/z) pair used to fit a calibration
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Calibrant {
+    pub tof: i64, // in ps
+    pub mz: f64,
+}
+
+/// which model to fit through the calibrants
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CalibrationModel {
+    Physical,
+    Polynomial { degree: usize },
+}
+
+/// conversion of time-of-flight (ps) to m/z
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MassCalibration {
+    /// the physical model t = t0 + k * sqrt(m/z), with t0 and t in us
+    Physical { t0: f64, k: f64 },
+    /// m/z as a polynomial of the time-of-flight in us, constant term first
+    Polynomial { coefficients: [f64; MAX_COEFFICIENTS] },
+}
+
+/// the quick (uncalibrated) conversion used before any calibration was available:
+/// y2=0.139*x.^2-1.413*x+3.686
+impl Default for MassCalibration {
+    fn default() -> MassCalibration {
+        MassCalibration::Polynomial { coefficients: [3.686, -1.413, 0.139, 0.0, 0.0] }
+    }
+}
+
+impl MassCalibration {
+    // HOT FUNCTION -> WORK TO OPTIMIZE!!!
+    pub fn time_to_mass(&self, time: i64) -> f64 {
+        let x = time as f64 / 1_000_000.0;
+        match self {
+            MassCalibration::Physical { t0, k } => {
+                let root = (x - t0) / k;
+                root * root.abs() // negative before t0 so these can be filtered
+            }
+            MassCalibration::Polynomial { coefficients } => {
+                coefficients.iter().rev().fold(0.0, |acc, c| acc * x + c)
+            }
+        }
+    }
+
+    /// the time of flight (ps) of an m/z; for polynomials the first crossing on a rising part of
+    /// the curve within MAX_TOF, None if there is none
+    pub fn mass_to_time(&self, mz: f64) -> Option<i64> {
+        match self {
+            MassCalibration::Physical { t0, k } => {
+                (mz >= 0.0).then(|| ((t0 + k * mz.sqrt()) * 1_000_000.0).round() as i64)
+            }
+            MassCalibration::Polynomial { .. } => {
+                let step = 1_000_000; // ps, coarse search for the bracket
+                let (mut lo, mut hi) = (0..MAX_TOF / step)
+                    .map(|i| (i * step, (i + 1) * step))
+                    .find(|&(a, b)| self.time_to_mass(a) < mz && self.time_to_mass(b) >= mz)?;
+                while hi - lo > 1 {
+                    let mid = (lo + hi) / 2;
+                    match self.time_to_mass(mid) < mz {
+                        true => lo = mid,
+                        false => hi = mid,
+                    }
+                }
+                Some(hi)
+            }
+        }
+    }
+
+    /// least squares fit of the given model through the calibrants
+    pub fn fit(model: CalibrationModel, calibrants: &[Calibrant]) -> Result<MassCalibration, Box<dyn Error>> {
+        let times: Vec<f64> = calibrants.iter().map(|c| c.tof as f64 / 1_000_000.0).collect();
+        match model {
+            CalibrationModel::Physical => {
+                let rows: Vec<Vec<f64>> = calibrants.iter().map(|c| vec![1.0, c.mz.sqrt()]).collect();
+                let fit = math::least_squares(&rows, &times).ok_or("not enough distinct calibrants")?;
+                Ok(MassCalibration::Physical { t0: fit[0], k: fit[1] })
+            }
+            CalibrationModel::Polynomial { degree } => {
+                if degree >= MAX_COEFFICIENTS {
+                    return Err(format!("polynomial degree {} is above {}", degree, MAX_COEFFICIENTS - 1).into());
+                }
+                let rows: Vec<Vec<f64>> =
+                    times.iter().map(|t| (0..=degree).map(|i| t.powi(i as i32)).collect()).collect();
+                let mzs: Vec<f64> = calibrants.iter().map(|c| c.mz).collect();
+                let fit = math::least_squares(&rows, &mzs).ok_or("not enough distinct calibrants")?;
+                let mut coefficients = [0.0; MAX_COEFFICIENTS];
+                coefficients[..fit.len()].copy_from_slice(&fit);
+                Ok(MassCalibration::Polynomial { coefficients })
+            }
+        }
+    }
+}
+
+/// a reference m/z matched to a found peak during automatic calibration
+#[derive(Copy, Clone, Debug)]
+pub struct CalibrationMatch {
+    pub reference: f64, // expected m/z
+    pub tof: i64,       // peak time in ps
+    pub mz: f64,        // m/z of the peak with the fitted calibration
+    pub rejected: bool, // outliers are excluded from the final fit
+}
+
+impl CalibrationMatch {
+    pub fn error_mda(&self) -> f64 { (self.mz - self.reference) * 1000.0 }
+
+    pub fn error_ppm(&self) -> f64 { (self.mz - self.reference) / self.reference * 1e6 }
+}
+
+pub struct CalibrationReport {
+    pub calibration: MassCalibration,
+    pub matches: Vec<CalibrationMatch>,
+}
+
+impl CalibrationReport {
+    /// root mean square error (ppm) of the matches used in the fit
+    pub fn rms_ppm(&self) -> f64 {
+        let used: Vec<f64> = self.matches.iter().filter(|m| !m.rejected).map(|m| m.error_ppm()).collect();
+        (used.iter().map(|e| e * e).sum::<f64>() / used.len() as f64).sqrt()
+    }
+}
+
+/// the physical model through two (tof, m/z) points; None if the tof does not increase with m/z
+fn two_point_calibration(a: (i64, f64), b: (i64, f64)) -> Option<MassCalibration> {
+    let (ta, tb) = (a.0 as f64 / 1_000_000.0, b.0 as f64 / 1_000_000.0);
+    let k = (tb - ta) / (b.1.sqrt() - a.1.sqrt());
+    (k > 0.0 && k.is_finite()).then(|| MassCalibration::Physical { t0: ta - k * a.1.sqrt(), k })
+}
+
+/// pairs each reference with the closest peak (in m/z) under a calibration, if within tolerance;
+/// a peak closest to several references is only paired with the closest of them
+fn match_references(calibration: &MassCalibration, peaks: &[i64], refs: &[f64], tolerance: f64) -> Vec<(i64, f64)> {
+    let mzs: Vec<f64> = peaks.iter().map(|&p| calibration.time_to_mass(p)).collect();
+    let mut matched: Vec<(usize, f64, f64)> = vec![]; // peak, reference and their distance (Da)
+    for &r in refs {
+        let (i, distance) = match mzs.iter().map(|mz| (mz - r).abs()).enumerate().min_by(|a, b| a.1.total_cmp(&b.1)) {
+            Some((i, distance)) if distance <= tolerance => (i, distance),
+            _ => continue,
+        };
+        match matched.iter_mut().find(|m| m.0 == i) {
+            Some(m) if distance < m.2 => *m = (i, r, distance),
+            Some(_) => (),
+            None => matched.push((i, r, distance)),
+        }
+    }
+    matched.into_iter().map(|(i, r, _)| (peaks[i], r)).collect()
+}
+
+/// the number of peaks (the largest by area) the pairs of `auto_calibrate` are taken from
+const CALIBRATION_ANCHORS: usize = 20;
+
+/// calibrates without calibrants: every pair of references is tried against every pair of the
+/// largest peaks (with the physical model), the assignment matching the most references (to any
+/// peak) within the tolerance (in Da) is fitted with the requested model, and outliers (beyond
+/// three robust standard deviations) are rejected and the fit repeated
+pub fn auto_calibrate(
+    peaks: &[Peak], references: &[f64], model: CalibrationModel, tolerance: f64,
+) -> Result<CalibrationReport, Box<dyn Error>> {
+    let mut refs = references.to_vec();
+    refs.sort_by(|a, b| a.total_cmp(b));
+    let mut anchors = peaks.to_vec();
+    anchors.sort_by(|a, b| b.area.total_cmp(&a.area));
+    let mut anchors: Vec<i64> = anchors.iter().take(CALIBRATION_ANCHORS).map(|p| p.apex).collect();
+    anchors.sort_unstable();
+    let mut peaks: Vec<i64> = peaks.iter().map(|p| p.apex).collect();
+    peaks.sort_unstable();
+    let (mut best, mut best_score) = (vec![], (0, f64::INFINITY));
+    for (ra, rb) in refs.iter().enumerate().flat_map(|(i, a)| refs[i + 1..].iter().map(move |b| (*a, *b))) {
+        for (i, &pa) in anchors.iter().enumerate() {
+            for &pb in anchors[i + 1..].iter() {
+                let calibration = match two_point_calibration((pa, ra), (pb, rb)) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                let matched = match_references(&calibration, &peaks, &refs, tolerance);
+                let error: f64 = matched.iter().map(|&(t, r)| (calibration.time_to_mass(t) - r).powi(2)).sum();
+                if (matched.len(), -error) > (best_score.0, -best_score.1) {
+                    best_score = (matched.len(), error);
+                    best = matched;
+                }
+            }
+        }
+    }
+    let minimum = match model {
+        CalibrationModel::Physical => 3,
+        CalibrationModel::Polynomial { degree } => degree + 2,
+    };
+    let mut matches: Vec<CalibrationMatch> = best
+        .iter()
+        .map(|&(tof, reference)| CalibrationMatch { reference, tof, mz: 0.0, rejected: false })
+        .collect();
+    loop {
+        let used: Vec<Calibrant> =
+            matches.iter().filter(|m| !m.rejected).map(|m| Calibrant { tof: m.tof, mz: m.reference }).collect();
+        if used.len() < minimum {
+            return Err(format!("only {} reference peaks matched, {} needed", used.len(), minimum).into());
+        }
+        let calibration = MassCalibration::fit(model, &used)?;
+        matches.iter_mut().for_each(|m| m.mz = calibration.time_to_mass(m.tof));
+        let mut errors: Vec<f64> = matches.iter().filter(|m| !m.rejected).map(|m| m.error_ppm().abs()).collect();
+        errors.sort_by(|a, b| a.total_cmp(b));
+        let limit = (3.0 * 1.4826 * errors[errors.len() / 2]).max(1.0); // ppm, from the median
+        let worst = matches
+            .iter_mut()
+            .filter(|m| !m.rejected && m.error_ppm().abs() > limit)
+            .max_by(|a, b| a.error_ppm().abs().total_cmp(&b.error_ppm().abs()));
+        match worst {
+            Some(m) if used.len() > minimum => m.rejected = true,
+            _ => return Ok(CalibrationReport { calibration, matches }),
+        }
+    }
+}
+
+/// the width of a mass window, either absolute or relative to its m/z
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Tolerance {
+    Da(f64),
+    Ppm(f64),
+}
+
+/// an ion image request: the hits within the tolerance (on either side) of an m/z
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MassWindow {
+    pub mz: f64,
+    pub tolerance: Tolerance,
+    pub label: Option<String>, // used for the file name instead of the m/z
+}
+
+impl MassWindow {
+    pub fn half_width(&self) -> f64 {
+        match self.tolerance {
+            Tolerance::Da(da) => da,
+            Tolerance::Ppm(ppm) => self.mz * ppm / 1_000_000.0,
+        }
+    }
+
+    /// the time of flight window (ps, start inclusive and end exclusive) under a calibration
+    pub fn time_window(&self, calibration: &MassCalibration) -> Option<(i64, i64)> {
+        let half_width = self.half_width();
+        let start = calibration.mass_to_time((self.mz - half_width).max(0.0))?;
+        Some((start, calibration.mass_to_time(self.mz + half_width)?))
+    }
+
+    /// the label made safe for file names, or the m/z with one decimal
+    pub fn name(&self) -> String {
+        match &self.label {
+            Some(label) => label.chars().map(|c| if c.is_alphanumeric() || "-_.+".contains(c) { c } else { '_' }).collect(),
+            None => format!("{:.1}mz", self.mz),
+        }
+    }
+}
+
+/// reads a mass list: one window per line as "m/z, tolerance[, label]" where the tolerance is in
+/// Da, or in ppm with a "ppm" suffix; empty lines and lines starting with '#' are skipped
+pub fn read_mass_list(path: &std::path::Path) -> Result<Vec<MassWindow>, Box<dyn Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut windows = vec![];
+    for (number, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('#')) {
+        let error = || format!("{:?} line {}: expected \"m/z, tolerance[, label]\"", path, number + 1);
+        let fields: Vec<&str> = line.splitn(3, ',').map(|f| f.trim()).collect();
+        if fields.len() < 2 {
+            return Err(error().into());
+        }
+        let mz = fields[0].parse().map_err(|_| error())?;
+        let tolerance = match fields[1].strip_suffix("ppm") {
+            Some(ppm) => Tolerance::Ppm(ppm.trim().parse().map_err(|_| error())?),
+            None => Tolerance::Da(fields[1].parse().map_err(|_| error())?),
+        };
+        let label = fields.get(2).filter(|l| !l.is_empty()).map(|l| l.to_string());
+        windows.push(MassWindow { mz, tolerance, label });
+    }
+    Ok(windows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibrants(calibration: &MassCalibration, tofs: &[i64]) -> Vec<Calibrant> {
+        tofs.iter().map(|&tof| Calibrant { tof, mz: calibration.time_to_mass(tof) }).collect()
+    }
+
+    #[test]
+    fn default_calibration_is_the_quick_conversion() {
+        let x: f64 = 20.0;
+        let legacy = 0.139 * x.powf(2.0) - 1.413 * x + 3.686;
+        assert!((MassCalibration::default().time_to_mass(20_000_000) - legacy).abs() < 1e-9);
+    }
+
+    #[test]
+    fn physical_fit_recovers_its_parameters() {
+        let truth = MassCalibration::Physical { t0: 0.35, k: 3.2 };
+        let pairs = calibrants(&truth, &[9_000_000, 15_000_000, 22_500_000, 31_000_000]);
+        match MassCalibration::fit(CalibrationModel::Physical, &pairs).unwrap() {
+            MassCalibration::Physical { t0, k } => {
+                assert!((t0 - 0.35).abs() < 1e-9 && (k - 3.2).abs() < 1e-9, "{} {}", t0, k);
+            }
+            other => panic!("fitted {:?}", other),
+        }
+        assert!(truth.time_to_mass(300_000) < 0.0); // before t0
+    }
+
+    #[test]
+    fn polynomial_fit_recovers_its_coefficients() {
+        let truth = MassCalibration::default();
+        let pairs = calibrants(&truth, &[12_000_000, 20_000_000, 26_000_000, 31_000_000, 40_000_000]);
+        let fit = MassCalibration::fit(CalibrationModel::Polynomial { degree: 2 }, &pairs).unwrap();
+        for &tof in &[10_000_000, 25_000_000, 50_000_000] {
+            assert!((fit.time_to_mass(tof) - truth.time_to_mass(tof)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn auto_calibrate_assigns_references_and_rejects_outliers() {
+        let truth = MassCalibration::Physical { t0: 0.42, k: 3.55 };
+        let tof = |mz: f64| ((0.42 + 3.55 * mz.sqrt()) * 1_000_000.0) as i64;
+        let references = [12.0, 15.0, 28.0, 43.0, 57.0, 77.0, 91.0, 105.0];
+        // 105 is missing from the data, 57 is shifted by 0.15 Da and there are unrelated peaks
+        let peak = |mz: f64, area: f64| Peak { apex: tof(mz), height: area, fwhm: 1000, area, snr: 10.0 };
+        let mut peaks: Vec<Peak> = [12.0, 15.0, 28.0, 43.0, 57.15, 77.0, 91.0].iter().map(|&m| peak(m, 100.0)).collect();
+        peaks.extend([peak(20.3, 50.0), peak(33.6, 50.0), peak(64.8, 50.0), peak(120.1, 50.0)]);
+        // small peaks are only matched, the pairs are taken from the large ones
+        peaks.extend((0..40).map(|i| peak(130.0 + i as f64 * 2.3, 1.0)));
+        let report = auto_calibrate(&peaks, &references, CalibrationModel::Physical, 0.5).unwrap();
+        assert_eq!(report.matches.len(), 7);
+        let rejected: Vec<f64> = report.matches.iter().filter(|m| m.rejected).map(|m| m.reference).collect();
+        assert_eq!(rejected, vec![57.0]);
+        assert!(report.rms_ppm() < 100.0, "{}", report.rms_ppm());
+        for mz in [10.0, 50.0, 100.0] {
+            assert!((report.calibration.time_to_mass(tof(mz)) - truth.time_to_mass(tof(mz))).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn references_are_matched_to_distinct_peaks() {
+        let calibration = MassCalibration::Physical { t0: 0.42, k: 3.55 };
+        let tof = |mz: f64| calibration.mass_to_time(mz).unwrap();
+        let peaks = [tof(28.0), tof(43.0)];
+        let matched = match_references(&calibration, &peaks, &[27.7, 28.1, 43.0], 0.5);
+        assert_eq!(matched, vec![(tof(28.0), 28.1), (tof(43.0), 43.0)]);
+    }
+
+    /// gaussian peaks (sigma in bins) on a flat background of `background` counts per bin
+    fn synthetic_spectrum(peaks: &[(i64, f64, f64)], background: f64, bins: usize) -> Spectrum {
+        let start = 10_000_000.0;
+        let counts = (0..bins)
+            .map(|i| {
+                let t = start + i as f64 * TIME_BIN_WIDTH;
+                let signal: f64 = peaks
+                    .iter()
+                    .map(|&(apex, height, sigma)| {
+                        height * (-0.5 * ((t - apex as f64) / (sigma * TIME_BIN_WIDTH)).powi(2)).exp()
+                    })
+                    .sum();
+                (signal + background).round() as u32
+            })
+            .collect();
+        Spectrum { binning: Binning::Time { width: TIME_BIN_WIDTH }, start, counts }
+    }
+
+    #[test]
+    fn find_peaks_reports_apex_width_area_and_snr() {
+        let peaks = [(12_000_000, 400.0, 4.0), (12_100_000, 100.0, 4.0), (14_000_000, 5.0, 4.0)];
+        let spectrum = synthetic_spectrum(&peaks, 25.0, 3000);
+        for smoothing in [
+            Smoothing::MovingAverage { window: 5 },
+            Smoothing::SavitzkyGolay { window: 11, order: 2 },
+            Smoothing::Gaussian { sigma: 1.5 },
+        ] {
+            let picking = PeakPicking { smoothing, ..Default::default() };
+            let found = find_peaks(&spectrum, &picking);
+            assert_eq!(found.len(), 2, "{:?}: {:?}", smoothing, found); // the third is within the noise
+            let fwhm = 2.3548 * 4.0 * TIME_BIN_WIDTH;
+            for (peak, &(apex, height, sigma)) in found.iter().zip(&peaks) {
+                assert!(((peak.apex - apex) as f64).abs() < TIME_BIN_WIDTH / 2.0, "{:?}: {:?}", smoothing, peak);
+                assert!((peak.fwhm as f64 / fwhm - 1.0).abs() < 0.15, "{:?}: {:?}", smoothing, peak);
+                let area = height * sigma * (2.0 * std::f64::consts::PI).sqrt();
+                assert!((peak.area / area - 1.0).abs() < 0.1, "{:?}: {:?}", smoothing, peak);
+                assert!((peak.snr - peak.height / 5.0).abs() < 1e-9); // sqrt of the background
+            }
+        }
+        let picking = PeakPicking { min_separation: 150_000, ..Default::default() };
+        assert_eq!(find_peaks(&spectrum, &picking).len(), 1);
+        let picking = PeakPicking { min_snr: 0.5, smoothing: Smoothing::Gaussian { sigma: 3.0 }, ..Default::default() };
+        assert_eq!(find_peaks(&spectrum, &picking).len(), 3);
+    }
+
+    #[test]
+    fn find_peaks_handles_edges_and_empty_spectra() {
+        let empty = Spectrum { binning: Binning::Time { width: TIME_BIN_WIDTH }, start: 0.0, counts: vec![] };
+        assert!(find_peaks(&empty, &PeakPicking::default()).is_empty());
+        let found = find_peaks(&synthetic_spectrum(&[(10_000_000, 300.0, 3.0)], 0.0, 40), &PeakPicking::default());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].apex, 10_000_000);
+    }
+
+    #[test]
+    fn peak_summaries_of_single_bin_peaks_have_no_resolving_power() {
+        let calibration = MassCalibration::Physical { t0: 0.42, k: 3.55 };
+        let peak = Peak { apex: 20_000_000, height: 10.0, fwhm: 0, area: 10.0, snr: 10.0 };
+        assert_eq!(PeakSummary::new(&peak, &calibration, 1).resolving_power, 0.0);
+        let summary = PeakSummary::new(&Peak { fwhm: 10_000, ..peak }, &calibration, 1);
+        assert!((summary.resolving_power - summary.mz / summary.fwhm_da).abs() < 1e-9);
+    }
+
+    #[test]
+    fn spectrum_bins_in_time_or_mass_within_the_range() {
+        let calibration = MassCalibration::Physical { t0: 0.0, k: 2.0 };
+        let config = SpectrumConfig { binning: Binning::Time { width: 260.0 }, tof_min: Some(1_000), tof_max: None };
+        assert_eq!(config.index(999, 10_000, &calibration), None);
+        assert_eq!(config.index(1_000, 10_000, &calibration), Some(0));
+        assert_eq!(config.index(1_521, 10_000, &calibration), Some(2));
+        assert_eq!(config.index(10_000, 10_000, &calibration), None);
+        let config = SpectrumConfig { binning: Binning::Mass { width: 0.5 }, ..Default::default() };
+        assert_eq!(config.index(20_000_000, 40_000_000, &calibration), Some(200)); // 100 m/z
+        assert_eq!(config.index(19_990_000, 40_000_000, &calibration), Some(199));
+        assert_eq!(config.index(-5, 40_000_000, &calibration), None);
+    }
+
+    #[test]
+    fn spectrum_configs_need_a_positive_bin_width() {
+        assert!(SpectrumConfig::default().validate().is_ok());
+        for width in [0.0, -1562.5, f64::NAN, f64::INFINITY] {
+            let config = SpectrumConfig { binning: Binning::Mass { width }, ..Default::default() };
+            assert!(config.validate().is_err(), "{}", width);
+        }
+    }
+
+    #[test]
+    fn gaussian_smoothing_needs_a_positive_sigma() {
+        let picking = |sigma| PeakPicking { smoothing: Smoothing::Gaussian { sigma }, ..Default::default() };
+        assert!(PeakPicking::default().validate().is_ok() && picking(0.5).validate().is_ok());
+        for sigma in [0.0, -3.0, f64::NAN, f64::INFINITY] {
+            assert!(picking(sigma).validate().is_err(), "{}", sigma);
+        }
+    }
+
+    #[test]
+    fn zero_padded_keeps_occupied_bins_and_their_neighbours() {
+        let spectrum =
+            Spectrum { binning: Binning::Mass { width: 0.5 }, start: 0.0, counts: vec![0, 0, 3, 0, 0, 0, 1, 2] };
+        let (axis, counts) = spectrum.zero_padded();
+        assert_eq!(axis, vec![0.5, 1.0, 1.5, 2.5, 3.0, 3.5]);
+        assert_eq!(counts, vec![0, 3, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn mass_to_time_inverts_both_models() {
+        let physical = MassCalibration::Physical { t0: 0.42, k: 3.55 };
+        for calibration in [physical, MassCalibration::default()] {
+            for tof in [12_000_000, 20_000_000, 31_000_123, 56_000_000] {
+                assert_eq!(calibration.mass_to_time(calibration.time_to_mass(tof)), Some(tof), "{:?}", calibration);
+            }
+        }
+        assert_eq!(physical.mass_to_time(-1.0), None);
+        assert_eq!(MassCalibration::default().mass_to_time(-1.0), None); // the polynomial stays positive
+    }
+
+    #[test]
+    fn mass_lists_are_read_with_da_and_ppm_tolerances() {
+        let path = std::env::temp_dir().join(format!("two_grids_mass_list_{}.csv", std::process::id()));
+        std::fs::write(&path, "# m/z, tolerance, label\n31.0, 0.2, CF+\n\n69.0, 500 ppm, CF3 (fragment)\n93.5,0.1\n")
+            .unwrap();
+        let windows = read_mass_list(&path).unwrap();
+        assert_eq!(windows.len(), 3);
+        assert_eq!((windows[0].half_width(), windows[0].name()), (0.2, String::from("CF+")));
+        assert!((windows[1].half_width() - 0.0345).abs() < 1e-12);
+        assert_eq!(windows[1].name(), "CF3__fragment_");
+        assert_eq!((windows[2].label.as_ref(), windows[2].name()), (None, String::from("93.5mz")));
+        let calibration = MassCalibration::Physical { t0: 0.42, k: 3.55 };
+        let (start, end) = windows[0].time_window(&calibration).unwrap();
+        assert!((calibration.time_to_mass(start) - 30.8).abs() < 1e-4 && (calibration.time_to_mass(end) - 31.2).abs() < 1e-4);
+        std::fs::write(&path, "31.0\n").unwrap();
+        assert!(read_mass_list(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fit_needs_enough_calibrants() {
+        let pairs = calibrants(&MassCalibration::default(), &[20_000_000, 26_000_000]);
+        assert!(MassCalibration::fit(CalibrationModel::Polynomial { degree: 2 }, &pairs).is_err());
+        assert!(MassCalibration::fit(CalibrationModel::Polynomial { degree: 5 }, &pairs).is_err());
+        assert!(MassCalibration::fit(CalibrationModel::Physical, &pairs[..1]).is_err());
+    }
+}
@@ -18,3 +18,45 @@ pub fn argmax_u32(slice: &[u32]) -> (usize, u32) {
         }
     })
 }
+
+/// linear least squares: finds the coefficients c minimizing |A c - y|; columns are scaled to unit
+/// maximum before solving the normal equations to keep them well conditioned
+pub fn least_squares(rows: &[Vec<f64>], ys: &[f64]) -> Option<Vec<f64>> {
+    let n = rows.first()?.len();
+    if rows.len() < n {
+        return None;
+    }
+    let scales: Vec<f64> = (0..n)
+        .map(|j| rows.iter().map(|r| r[j].abs()).fold(0.0, f64::max))
+        .map(|s| if s > 0.0 { s } else { 1.0 })
+        .collect();
+    // normal equations, augmented with the right hand side
+    let mut m = vec![vec![0.0; n + 1]; n];
+    for (row, y) in rows.iter().zip(ys) {
+        for i in 0..n {
+            for j in 0..n {
+                m[i][j] += row[i] / scales[i] * row[j] / scales[j];
+            }
+            m[i][n] += row[i] / scales[i] * y;
+        }
+    }
+    // gaussian elimination with partial pivoting
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&a, &b| m[a][col].abs().total_cmp(&m[b][col].abs()))?;
+        if m[pivot][col].abs() < 1e-12 {
+            return None; // singular, e.g., too few distinct points
+        }
+        m.swap(col, pivot);
+        let pivot_row = m[col].clone();
+        for row in m.iter_mut().skip(col + 1) {
+            let factor = row[col] / pivot_row[col];
+            row.iter_mut().zip(&pivot_row).skip(col).for_each(|(v, p)| *v -= factor * p);
+        }
+    }
+    let mut coefficients = vec![0.0; n];
+    for i in (0..n).rev() {
+        let tail: f64 = ((i + 1)..n).map(|j| m[i][j] * coefficients[j]).sum();
+        coefficients[i] = (m[i][n] - tail) / m[i][i];
+    }
+    Some(coefficients.iter().zip(&scales).map(|(c, s)| c / s).collect())
+}
@@ -0,0 +1,171 @@
+use std::{
+    error::Error,
+    io::{BufWriter, Write},
+};
+
+use crate::writer;
+
+// tiff field types
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const ASCII: u16 = 2;
+const RATIONAL: u16 = 5;
+
+/// one plane of the stack with the name of its channel (e.g., the m/z and the window it spans)
+pub struct Plane<'a> {
+    pub name: String,
+    pub counts: &'a [u32],
+}
+
+/// the OME-XML describing the planes as the channels of one image
+fn ome_xml(name: &str, planes: &[Plane], width: usize, height: usize, pixels_per_mm: f64) -> String {
+    let size = 1000.0 / pixels_per_mm; // um per pixel
+    let channels: String = planes
+        .iter()
+        .enumerate()
+        .map(|(i, p)| format!(r#"      <Channel ID="Channel:0:{}" Name="{}" SamplesPerPixel="1"/>"#, i, writer::escape_xml(&p.name)) + "\n")
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<OME xmlns="http://www.openmicroscopy.org/Schemas/OME/2016-06" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.openmicroscopy.org/Schemas/OME/2016-06 http://www.openmicroscopy.org/Schemas/OME/2016-06/ome.xsd" Creator="two_grids_script">
+  <Image ID="Image:0" Name="{name}">
+    <Pixels ID="Pixels:0" DimensionOrder="XYCZT" Type="uint32" BigEndian="false" SizeX="{width}" SizeY="{height}" SizeC="{c}" SizeZ="1" SizeT="1" PhysicalSizeX="{size}" PhysicalSizeXUnit="µm" PhysicalSizeY="{size}" PhysicalSizeYUnit="µm">
+{channels}      <TiffData IFD="0" PlaneCount="{c}"/>
+    </Pixels>
+  </Image>
+</OME>
+"#,
+        name = writer::escape_xml(name),
+        c = planes.len(),
+    )
+}
+
+/// an image file directory entry; values of up to four bytes are stored in place
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: Vec<u8>,
+}
+
+impl Entry {
+    fn short(tag: u16, v: u16) -> Entry { Entry { tag, kind: SHORT, count: 1, value: v.to_le_bytes().to_vec() } }
+
+    fn long(tag: u16, v: u32) -> Entry { Entry { tag, kind: LONG, count: 1, value: v.to_le_bytes().to_vec() } }
+
+    fn rational(tag: u16, numerator: u32, denominator: u32) -> Entry {
+        let value = [numerator.to_le_bytes(), denominator.to_le_bytes()].concat();
+        Entry { tag, kind: RATIONAL, count: 1, value }
+    }
+
+    fn ascii(tag: u16, text: &str) -> Entry {
+        let value = [text.as_bytes(), &[0]].concat();
+        Entry { tag, kind: ASCII, count: value.len() as u32, value }
+    }
+}
+
+/// writes the planes as the pages of a little endian, uncompressed 32 bit tiff, with the OME-XML
+/// (channel names and the physical pixel size) in the description of the first page so that
+/// Fiji (Bio-Formats) and QuPath open them as the channels of one image; the raw counts are kept
+pub fn save_ome_tiff(
+    path: &std::path::Path, planes: &[Plane], width: usize, height: usize, pixels_per_mm: f64,
+) -> Result<(), Box<dyn Error>> {
+    let plane_bytes = width * height * 4;
+    if let Some(p) = planes.iter().find(|p| p.counts.len() != width * height) {
+        return Err(format!("plane {} has {} pixels instead of {}x{}", p.name, p.counts.len(), width, height).into());
+    }
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let xml = ome_xml(name.trim_end_matches(".ome.tif"), planes, width, height, pixels_per_mm);
+    let size = planes.len() as u64 * (plane_bytes as u64 + 256) + xml.len() as u64; // directories are < 256 bytes
+    if size > u32::MAX as u64 {
+        return Err("the stack is too large for a (non Big) tiff, save fewer windows".into());
+    }
+    let pixels_per_cm = (pixels_per_mm * 10.0 * 1000.0).round() as u32; // as a rational over 1000
+    let mut file = BufWriter::new(std::fs::File::create(path)?);
+    // header, then each page as its pixels followed by its directory (and the values not in place)
+    file.write_all(b"II*\0")?;
+    let mut offset: u64 = 8;
+    file.write_all(&((offset + plane_bytes as u64) as u32).to_le_bytes())?;
+    for (i, plane) in planes.iter().enumerate() {
+        let strip = offset;
+        plane.counts.iter().try_for_each(|c| file.write_all(&c.to_le_bytes()))?;
+        offset += plane_bytes as u64; // a multiple of four, so the directory starts on a word boundary
+        let mut entries = vec![
+            Entry::long(256, width as u32),
+            Entry::long(257, height as u32),
+            Entry::short(258, 32),
+            Entry::short(259, 1), // no compression
+            Entry::short(262, 1), // black is zero
+            Entry::long(273, strip as u32),
+            Entry::short(277, 1),
+            Entry::long(278, height as u32),
+            Entry::long(279, plane_bytes as u32),
+            Entry::rational(282, pixels_per_cm, 1000),
+            Entry::rational(283, pixels_per_cm, 1000),
+            Entry::short(296, 3), // centimetres
+            Entry::short(339, 1), // unsigned integers
+        ];
+        if i == 0 {
+            entries.insert(5, Entry::ascii(270, &xml));
+        }
+        let directory_size = 2 + 12 * entries.len() as u64 + 4;
+        let mut extra = offset + directory_size; // where values that do not fit in place go
+        let mut overflow = vec![];
+        file.write_all(&(entries.len() as u16).to_le_bytes())?;
+        for entry in entries.iter() {
+            file.write_all(&entry.tag.to_le_bytes())?;
+            file.write_all(&entry.kind.to_le_bytes())?;
+            file.write_all(&entry.count.to_le_bytes())?;
+            if entry.value.len() <= 4 {
+                file.write_all(&[&entry.value[..], &vec![0; 4 - entry.value.len()]].concat())?;
+            } else {
+                file.write_all(&(extra as u32).to_le_bytes())?;
+                overflow.extend(&entry.value);
+                extra += entry.value.len() as u64;
+                if extra % 2 == 1 {
+                    overflow.push(0);
+                    extra += 1;
+                }
+            }
+        }
+        let next = if i + 1 < planes.len() { extra + plane_bytes as u64 } else { 0 }; // after its pixels
+        file.write_all(&(next as u32).to_le_bytes())?;
+        file.write_all(&overflow)?;
+        offset = extra;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stacks_decode_as_pages_with_ome_xml() {
+        let path = std::env::temp_dir().join(format!("two_grids_stack_{}.ome.tif", std::process::id()));
+        let tic: Vec<u32> = (0..12).collect();
+        let ion = vec![70_000u32; 12];
+        let planes = [
+            Plane { name: String::from("TIC"), counts: &tic },
+            Plane { name: String::from("bars <60.9 m/z>"), counts: &ion },
+        ];
+        save_ome_tiff(&path, &planes, 4, 3, 20.0).unwrap();
+        let mut decoder = tiff::decoder::Decoder::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (4, 3));
+        let xml = decoder.get_tag_ascii_string(tiff::tags::Tag::ImageDescription).unwrap();
+        assert!(xml.contains(r#"SizeC="2""#) && xml.contains(r#"PhysicalSizeX="50""#));
+        assert!(xml.contains(r#"Name="bars &lt;60.9 m/z&gt;""#));
+        let pixels = |decoder: &mut tiff::decoder::Decoder<_>| match decoder.read_image().unwrap() {
+            tiff::decoder::DecodingResult::U32(pixels) => pixels,
+            _ => panic!("not 32 bit"),
+        };
+        assert_eq!(pixels(&mut decoder), tic);
+        assert!(decoder.more_images());
+        decoder.next_image().unwrap();
+        assert_eq!(pixels(&mut decoder), ion);
+        assert!(!decoder.more_images());
+        assert!(save_ome_tiff(&path, &planes, 3, 3, 20.0).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
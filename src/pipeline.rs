@@ -0,0 +1,264 @@
+use std::error::Error;
+
+use rayon::prelude::*;
+
+use crate::{config::RunConfig, datacube, image, imzml, imzml_reader, mass, ometiff, render, writer};
+
+/// runs the full processing of a .tpx3c file: the spectrum report, the total ion count image, the
+/// spectra of the regions of interest, one ion image per found peak and per mass list entry (and
+/// optionally all of them as one OME-TIFF stack), the peak table and optionally an imzML file and
+/// a datacube, all written next to the input file together with the run config that was used;
+/// returns the found peaks
+pub fn run(path: &std::path::Path, run_config: &RunConfig) -> Result<Vec<mass::Peak>, Box<dyn Error>> {
+    run_config.validate()?; // also for configs that were not loaded from a file
+    let base_name = path.file_stem().unwrap().to_str().unwrap();
+    let mut run_config = run_config.clone();
+    run_config.calibrate()?;
+    let mut config = run_config.image;
+
+    // For quick plotting of the mass spectrum
+    let now = std::time::Instant::now();
+    let spectrum = mass::spectrum(path, Some(config.tof_pulse_length), &config.spectrum, &config.calibration, &[])?;
+    writer::plotly_spectra(path, &spectrum)?;
+    println!("plotly took {} ms", now.elapsed().as_millis());
+
+    // make the image structure
+    let mut image_data = image::Image {
+        tpx3_path: path.to_path_buf(),
+        config,
+        meta: image::Metadata { ..Default::default() },
+    };
+
+    if let Some(stage_log) = &run_config.stage_log {
+        image_data.coordinates_from_stage_log(stage_log)?;
+    }
+    image_data.auto_generate_coordinates().unwrap();
+    image_data.auto_generate_dead_pixels().unwrap();
+    image_data.auto_generate_mass_list()?.unwrap();
+
+    let fname = path.with_file_name(base_name.to_owned() + "_tic.png");
+    let buffer = image_data.to_buffer::<u32>().unwrap();
+    // save total ion count image
+    let scaling = config.scaling.resolve([&buffer[..]]);
+    writer::save_scaled_png(&buffer, config.cols(), config.rows(), &fname, scaling)?;
+    save_color_png(&buffer, &fname, "TIC", &config, scaling, &run_config.presentation)?;
+    if !run_config.rois.is_empty() {
+        let spectra = image_data.roi_spectra(&run_config.rois)?;
+        writer::plotly_roi_spectra(path, &run_config.rois, &spectra)?;
+    }
+    let found_peaks = image_data.meta.found_peaks.take().unwrap();
+    let peak_times: Vec<i64> = found_peaks.iter().map(|p| p.apex).collect();
+    match run_config.auto_calibrate(&found_peaks) {
+        Ok(Some(report)) => {
+            let fname = path.with_file_name(base_name.to_owned() + "_calibration_report.csv");
+            writer::save_calibration_report(&report, &fname)?;
+        }
+        Ok(None) => (),
+        Err(e) => println!("automatic calibration failed, keeping {:?}: {}", config.calibration, e),
+    }
+    config.calibration = run_config.image.calibration;
+    run_config.save(&path.with_file_name(base_name.to_owned() + "_run_config.json"))?;
+    // ion images of the found peaks and of the mass list, all made in one pass
+    let (windows, names, labels) = ion_windows(&peak_times, &run_config, &config.calibration)?;
+    let now = std::time::Instant::now();
+    let buffers = image_data.windows_to_buffers::<u32>(&windows)?;
+    println!("{} ion images took {} ms", windows.len(), now.elapsed().as_millis());
+    let page = (config.cols() * config.rows()) as usize;
+    let scaling = config.scaling.resolve(buffers.chunks(page)); // a shared range spans all ion images
+    // the errors are turned into strings to send them out of the threads
+    let pixels: Vec<usize> = buffers
+        .par_chunks(page)
+        .zip(names.par_iter().zip(&labels))
+        .map(|(buffer, (name, label))| {
+            let fname = path.with_file_name(format!("{}_{}.png", base_name, name));
+            writer::save_scaled_png(buffer, config.cols(), config.rows(), &fname, scaling).map_err(|e| e.to_string())?;
+            save_color_png(buffer, &fname, label, &config, scaling, &run_config.presentation).map_err(|e| e.to_string())?;
+            Ok(buffer.iter().filter(|&&p| p > 0).count())
+        })
+        .collect::<Result<_, String>>()?;
+    if run_config.ome_tiff {
+        // the raw counts of the tic and the ion images as the channels of one image
+        let mut planes = vec![ometiff::Plane { name: String::from("TIC"), counts: &buffer }];
+        for ((window, label), counts) in windows.iter().zip(&labels).zip(buffers.chunks(page)) {
+            let (low, high) = (config.calibration.time_to_mass(window.0), config.calibration.time_to_mass(window.1));
+            let name = format!("{} ({:.2}-{:.2} m/z, {}-{} ps)", label, low, high, window.0, window.1);
+            planes.push(ometiff::Plane { name, counts });
+        }
+        let fname = path.with_file_name(base_name.to_owned() + "_stack.ome.tif");
+        ometiff::save_ome_tiff(&fname, &planes, config.cols() as usize, config.rows() as usize, config.pixels_per_mm)?;
+    }
+    for overlay in run_config.presentation.overlays.iter() {
+        let page_of = |name: &Option<String>| {
+            let i = names.iter().position(|n| Some(n) == name.as_ref())?;
+            Some(&buffers[page * i..page * (i + 1)])
+        };
+        for name in [&overlay.red, &overlay.green, &overlay.blue].iter().filter_map(|n| n.as_ref()) {
+            if !names.contains(name) {
+                println!("overlay {}: there is no ion image {}", overlay.name, name);
+            }
+        }
+        let channels = [page_of(&overlay.red), page_of(&overlay.green), page_of(&overlay.blue)];
+        let (cols, rows) = (config.cols() as usize, config.rows() as usize);
+        let mut image = render::RgbImage::overlay(channels, cols, rows, scaling);
+        annotate(&mut image, &overlay.name, &config, &run_config.presentation);
+        let fname = path.with_file_name(format!("{}_{}.png", base_name, overlay.name));
+        let text = [("scaling", format!("{:?}", scaling)), ("overlay", format!("{:?}", overlay))];
+        writer::save_rgb_png(&image, &fname, &text)?;
+    }
+    let table: Vec<mass::PeakSummary> = found_peaks
+        .iter()
+        .zip(pixels) // the mass list images follow those of the peaks
+        .map(|(peak, pixels)| mass::PeakSummary::new(peak, &config.calibration, pixels))
+        .collect();
+    writer::save_peak_table(&table, &path.with_file_name(base_name.to_owned() + "_report_peaks.csv"))?;
+    image_data.config.calibration = config.calibration;
+    let cube = match &run_config.datacube {
+        Some(cube_config) => Some(datacube::Datacube::create(&image_data, &path.with_extension("zarr"), cube_config)?),
+        None => None,
+    };
+    if let Some(imzml_config) = run_config.imzml {
+        image_data.meta.found_peaks = Some(found_peaks.clone()); // the peak list of centroid spectra
+        let mut imzml_data = imzml::IMZMLMaker::new(image_data, imzml_config)?;
+        imzml_data.datacube = cube; // filled in the same pass over the events
+        imzml_data.stream_convert_and_save()?;
+    } else if let Some(cube) = cube {
+        cube.fill(&image_data)?;
+    }
+    Ok(found_peaks)
+}
+
+/// time of flight windows with the file names and the labels of their ion images
+type IonWindows = (Vec<(i64, i64)>, Vec<String>, Vec<String>);
+
+/// the windows of the ion images, of the found peaks (+/- `peak_time_window`, the events centroid
+/// imzML spectra assign to them) and then of the mass list
+fn ion_windows(
+    peak_times: &[i64], run_config: &RunConfig, calibration: &mass::MassCalibration,
+) -> Result<IonWindows, Box<dyn Error>> {
+    let ptw = run_config.image.peak_time_window;
+    let mut windows: Vec<(i64, i64)> = peak_times.iter().map(|&pt| (pt - ptw, pt + ptw)).collect();
+    let mut names: Vec<String> =
+        peak_times.iter().map(|&pt| format!("{:.1}mz", calibration.time_to_mass(pt))).collect();
+    let mut labels: Vec<String> =
+        peak_times.iter().map(|&pt| format!("{:.1} m/z", calibration.time_to_mass(pt))).collect();
+    if let Some(mass_list) = &run_config.mass_list {
+        for window in mass::read_mass_list(mass_list)? {
+            match window.time_window(calibration) {
+                Some(time_window) => {
+                    windows.push(time_window);
+                    names.push(window.name());
+                    labels.push(window.label.clone().unwrap_or_else(|| format!("{:.1} m/z", window.mz)));
+                }
+                None => println!("m/z {} is outside of the calibrated range, no image made", window.mz),
+            }
+        }
+    }
+    // the images are saved (and found by overlays) by name, so a repeated one gets a suffix
+    for i in 0..names.len() {
+        let (name, mut n) = (names[i].clone(), 1);
+        while names[..i].contains(&names[i]) {
+            n += 1;
+            names[i] = format!("{}_{}", name, n);
+        }
+        if names[i] != name {
+            println!("the ion image {} is saved as {}", name, names[i]);
+        }
+    }
+    Ok((windows, names, labels))
+}
+
+/// checks the imzML file written by an earlier run of a .tpx3c file (with the run config that run
+/// saved, so the same calibration and crop) against its events: the file is verified and its total
+/// ion count image and ion images are compared with those made from the events, written to
+/// "<name>_imzml_validation.csv"; returns the comparisons
+pub fn validate(
+    path: &std::path::Path, run_config: &RunConfig,
+) -> Result<Vec<imzml_reader::Comparison>, Box<dyn Error>> {
+    let base_name = path.file_stem().unwrap().to_str().unwrap();
+    let mut image_data = image::Image {
+        tpx3_path: path.to_path_buf(),
+        config: run_config.image,
+        meta: image::Metadata { ..Default::default() },
+    };
+    if let Some(stage_log) = &run_config.stage_log {
+        image_data.coordinates_from_stage_log(stage_log)?;
+    }
+    image_data.auto_generate_coordinates()?;
+    image_data.auto_generate_dead_pixels()?;
+    let found_peaks = image_data.auto_generate_mass_list()?.unwrap_or_default();
+    let peak_times: Vec<i64> = found_peaks.iter().map(|p| p.apex).collect();
+    let (windows, names, _) = ion_windows(&peak_times, run_config, &run_config.image.calibration)?;
+    let crop = run_config.imzml.as_ref().and_then(|c| c.crop);
+    let comparisons = imzml_reader::validate(&image_data, crop, &windows, &names)?;
+    for c in comparisons.iter() {
+        match c.matches() {
+            true => println!("{}: matches ({} counts)", c.name, c.expected),
+            false => println!(
+                "{}: {} pixels differ (by up to {}), {} counts instead of {}",
+                c.name, c.differing_pixels, c.max_difference, c.found, c.expected
+            ),
+        }
+    }
+    writer::save_validation_report(&comparisons, &path.with_file_name(base_name.to_owned() + "_imzml_validation.csv"))?;
+    Ok(comparisons)
+}
+
+fn annotate(image: &mut render::RgbImage, label: &str, config: &image::Config, presentation: &render::Presentation) {
+    if presentation.label {
+        image.draw_label(label);
+    }
+    if presentation.scale_bar {
+        image.draw_scale_bar(config.pixels_per_mm);
+    }
+}
+
+/// saves the colormapped version of an image (if a colormap is set) next to it, e.g.,
+/// "two_grids_tic_viridis.png" for "two_grids_tic.png"
+fn save_color_png(
+    buffer: &[u32], fname: &std::path::Path, label: &str, config: &image::Config, scaling: image::Scaling,
+    presentation: &render::Presentation,
+) -> Result<(), Box<dyn Error>> {
+    let colormap = match presentation.colormap {
+        Some(colormap) => colormap,
+        None => return Ok(()),
+    };
+    let mut image = render::RgbImage::colorize(buffer, config.cols() as usize, scaling, colormap);
+    annotate(&mut image, label, config, presentation);
+    let stem = fname.file_stem().unwrap().to_str().unwrap();
+    let colored = fname.with_file_name(format!("{}_{}.png", stem, format!("{:?}", colormap).to_lowercase()));
+    let range = scaling.range(buffer);
+    let text = [
+        ("scaling", format!("{:?}", scaling)),
+        ("range", format!("{} {}", range.0, range.1)),
+        ("colormap", format!("{:?}", colormap)),
+    ];
+    writer::save_rgb_png(&image, &colored, &text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_ion_image_names_get_a_suffix() {
+        let path = std::env::temp_dir().join(format!("two_grids_mass_list_{}.txt", std::process::id()));
+        std::fs::write(&path, "31.0, 0.2\n69.0, 50 ppm, CF3\n50.0, 0.2, CF3\n").unwrap();
+        let mut run_config = RunConfig { mass_list: Some(path.clone()), ..Default::default() };
+        run_config.image.calibration = mass::MassCalibration::Physical { t0: 1.0, k: 3.0 };
+        let calibration = run_config.image.calibration;
+        let peak_times = [calibration.mass_to_time(31.0).unwrap(), calibration.mass_to_time(45.0).unwrap()];
+        let (windows, names, labels) = ion_windows(&peak_times, &run_config, &calibration).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(names, ["31.0mz", "45.0mz", "31.0mz_2", "CF3", "CF3_2"]);
+        assert_eq!(labels, ["31.0 m/z", "45.0 m/z", "31.0 m/z", "CF3", "CF3"]);
+        assert_eq!(windows.len(), 5);
+    }
+
+    #[test]
+    fn runs_check_their_config_first() {
+        let mut run_config = RunConfig::default();
+        run_config.image.spectrum.binning = mass::Binning::Time { width: 0.0 };
+        let error = run(std::path::Path::new("missing.tpx3c"), &run_config).unwrap_err();
+        assert!(error.to_string().contains("bin width"), "{}", error);
+    }
+}
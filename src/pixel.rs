@@ -1,20 +1,28 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufWriter, Write},
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+};
 
+/// the m/z values of the events in a pixel (for continuous spectra, the indices of their bins)
 pub struct Pixel(Vec<f32>);
 
 impl Pixel {
     pub fn empty() -> Pixel { Pixel(vec![]) }
 
-    pub fn to_vecs(&mut self) -> (Vec<f32>, Vec<i16>) {
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_vecs(&mut self) -> (Vec<f32>, Vec<u32>) {
         self.sort_by(|a, b| a.total_cmp(b));
-        let pixel_divisors: Vec<f32> = self.iter().map(|x| 10f32.powf(5.0 - x.log(10.0))).collect();
+        // five significant digits (x * 10^(5 - log10(x)) would map every m/z to 10^5)
+        let pixel_divisors: Vec<f32> = self.iter().map(|x| 10f32.powi(4 - x.log10().floor() as i32)).collect();
         let pixels: Vec<u64> =
             self.iter().zip(&pixel_divisors).map(|(x, div)| (x * div) as u64).collect();
         let (mut mzs, mut intensities) = (vec![], vec![]);
         let mut prev = 0;
         for (&mz, div) in pixels.iter().zip(&pixel_divisors) {
             if mz > prev {
-                mzs.push(((mz as f32) / div) as f32);
+                mzs.push((mz as f32) / div);
                 intensities.push(1);
             } else if mz == 0 {
                 intensities.push(1);
@@ -25,6 +33,17 @@ impl Pixel {
         }
         (mzs, intensities)
     }
+
+    /// the number of events in each of `bins` bins, when the pixel holds bin indices
+    pub fn to_counts(&self, bins: usize) -> Vec<u32> {
+        let mut counts = vec![0; bins];
+        for &bin in self.iter() {
+            if let Some(count) = counts.get_mut(bin as usize) {
+                *count += 1;
+            }
+        }
+        counts
+    }
 }
 
 impl Deref for Pixel {
@@ -40,24 +59,199 @@ impl DerefMut for Pixel {
 pub struct PixelSpan {
     pub pixel_added: bool,
     pub empty_pass_count: usize,
+    pub events: usize,
     pub pixels: Vec<Pixel>,
 }
 
 impl PixelSpan {
     pub fn empty(pixel_count: usize) -> PixelSpan {
         let pixels: Vec<Pixel> = (0..pixel_count).map(|_| Pixel::empty()).collect();
-        PixelSpan { pixel_added: false, empty_pass_count: 0, pixels }
+        PixelSpan { pixel_added: false, empty_pass_count: 0, events: 0, pixels }
     }
 
     pub fn add_mz(&mut self, mz: f32, pixel_index: usize) {
         self.empty_pass_count = 0;
         self.pixel_added = true;
+        self.events += 1;
         self.pixels[pixel_index].push(mz);
     }
 
+    /// the memory the span holds, estimated from its pixels and events (not the spare capacity)
+    pub fn bytes(&self) -> usize {
+        self.pixels.len() * std::mem::size_of::<Pixel>() + self.events * std::mem::size_of::<f32>()
+    }
+
     // updates internal information and returns true if ready to write.
     pub fn update_end_pass(&mut self) {
         self.empty_pass_count = if self.pixel_added { 0 } else { self.empty_pass_count + 1 };
         self.pixel_added = false;
     }
 }
+
+/// the rows of an image being accumulated: they are kept in memory up to a budget (in bytes, as
+/// estimated by `PixelSpan::bytes`), beyond it the rows idle for the most passes (then the largest)
+/// are appended to a file per row until half the budget is used; `take` merges them back. The
+/// directory of the files is removed when the store is created (it can only hold the rows of a run
+/// that failed) and when it is dropped
+pub struct RowStore {
+    spans: HashMap<usize, PixelSpan>,
+    cols: usize,
+    dir: PathBuf, // of the spilled rows, created when the first row is spilled
+    budget: usize,
+    in_memory: usize,
+    spilled: HashSet<usize>,
+    pub peak: usize,         // the most bytes held in memory
+    pub spilled_rows: usize, // how many times a row was spilled
+    pub spilled_bytes: u64,  // written to the files of the spilled rows
+}
+
+impl RowStore {
+    pub fn new(dir: PathBuf, cols: usize, budget: usize) -> Result<RowStore, std::io::Error> {
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        Ok(RowStore {
+            spans: HashMap::new(),
+            cols,
+            dir,
+            budget,
+            in_memory: 0,
+            spilled: HashSet::new(),
+            peak: 0,
+            spilled_rows: 0,
+            spilled_bytes: 0,
+        })
+    }
+
+    pub fn add(&mut self, row: usize, col: usize, value: f32) -> Result<(), std::io::Error> {
+        let cols = self.cols;
+        let span = self.spans.entry(row).or_insert_with(|| PixelSpan::empty(cols));
+        self.in_memory += if span.events == 0 { span.bytes() } else { 0 } + std::mem::size_of::<f32>();
+        span.add_mz(value, col);
+        self.peak = self.peak.max(self.in_memory);
+        if self.in_memory > self.budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// counts the passes the rows in memory have not changed in
+    pub fn end_pass(&mut self) { self.spans.values_mut().for_each(|span| span.update_end_pass()); }
+
+    fn spill(&mut self) -> Result<(), std::io::Error> {
+        let mut rows: Vec<(usize, usize, usize)> =
+            self.spans.iter().map(|(&row, span)| (span.empty_pass_count, span.events, row)).collect();
+        rows.sort_unstable_by(|a, b| b.cmp(a));
+        std::fs::create_dir_all(&self.dir)?;
+        for (_, _, row) in rows {
+            if self.in_memory <= self.budget / 2 {
+                break;
+            }
+            let span = self.spans.remove(&row).unwrap();
+            let path = self.dir.join(format!("{}.bin", row));
+            let file = match self.spilled.insert(row) {
+                true => std::fs::File::create(path)?, // the first spill of the row
+                false => std::fs::OpenOptions::new().append(true).open(path)?,
+            };
+            let mut file = BufWriter::new(file);
+            for (col, pixel) in span.pixels.iter().enumerate() {
+                for value in pixel.iter() {
+                    file.write_all(&(col as u32).to_le_bytes())?;
+                    file.write_all(&value.to_le_bytes())?;
+                }
+            }
+            file.flush()?;
+            self.in_memory -= span.bytes();
+            self.spilled_rows += 1;
+            self.spilled_bytes += 8 * span.events as u64;
+        }
+        Ok(())
+    }
+
+    /// removes a row, with the events spilled to its file merged back (empty if it has none)
+    pub fn take(&mut self, row: usize) -> Result<PixelSpan, std::io::Error> {
+        let mut span = match self.spans.remove(&row) {
+            Some(span) => {
+                self.in_memory -= span.bytes();
+                span
+            }
+            None => PixelSpan::empty(self.cols),
+        };
+        if self.spilled.remove(&row) {
+            let path = self.dir.join(format!("{}.bin", row));
+            for record in std::fs::read(&path)?.chunks_exact(8) {
+                let col = u32::from_le_bytes([record[0], record[1], record[2], record[3]]) as usize;
+                span.add_mz(f32::from_le_bytes([record[4], record[5], record[6], record[7]]), col);
+            }
+            std::fs::remove_file(path)?;
+        }
+        Ok(span)
+    }
+
+    /// removes the directory of the spilled rows
+    pub fn finish(self) -> Result<(), std::io::Error> {
+        match self.dir.exists() {
+            true => std::fs::remove_dir_all(&self.dir),
+            false => Ok(()),
+        }
+    }
+}
+
+/// the spilled rows of a run that stopped on an error are removed too
+impl Drop for RowStore {
+    fn drop(&mut self) {
+        if self.dir.exists() {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_over_the_budget_are_spilled_and_merged_back() {
+        let dir = std::env::temp_dir().join(format!("two_grids_spill_{}", std::process::id()));
+        let row_bytes = 4 * std::mem::size_of::<Pixel>();
+        let mut store = RowStore::new(dir.clone(), 4, 2 * row_bytes + 40).unwrap();
+        store.add(0, 1, 50.0).unwrap();
+        store.end_pass();
+        store.add(1, 2, 60.0).unwrap();
+        store.add(1, 2, 61.0).unwrap();
+        assert_eq!(store.spilled_rows, 0);
+        store.add(2, 3, 70.0).unwrap(); // over the budget: row 0 is idle the longest, then row 1 the largest
+        assert_eq!((store.spilled_rows, store.spilled_bytes), (2, 24));
+        assert_eq!((store.peak, store.in_memory), (3 * row_bytes + 16, row_bytes + 4));
+        store.add(0, 0, 40.0).unwrap(); // revisited after being spilled
+
+        let rows: Vec<PixelSpan> = (0..4).map(|row| store.take(row).unwrap()).collect();
+        let values = |span: &PixelSpan| -> Vec<Vec<f32>> { span.pixels.iter().map(|p| p.to_vec()).collect() };
+        let mut first = values(&rows[0]);
+        first.iter_mut().for_each(|p| p.sort_by(|a, b| a.total_cmp(b)));
+        assert_eq!(first, vec![vec![40.0], vec![50.0], vec![], vec![]]);
+        assert_eq!(values(&rows[1])[2], vec![60.0, 61.0]);
+        assert_eq!(values(&rows[2])[3], vec![70.0]);
+        assert_eq!((rows[3].events, store.in_memory), (0, 0));
+        store.finish().unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn rows_left_by_a_failed_run_are_not_merged() {
+        let dir = std::env::temp_dir().join(format!("two_grids_stale_spill_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("0.bin"), [1u32.to_le_bytes(), 99f32.to_le_bytes()].concat()).unwrap();
+        let mut store = RowStore::new(dir.clone(), 2, 0).unwrap();
+        assert!(!dir.exists());
+        store.add(0, 0, 50.0).unwrap(); // spilled right away
+        store.add(0, 0, 51.0).unwrap();
+        assert_eq!(store.spilled_rows, 2);
+        let values: Vec<Vec<f32>> = store.take(0).unwrap().pixels.iter().map(|p| p.to_vec()).collect();
+        assert_eq!(values, vec![vec![50.0, 51.0], vec![]]);
+        store.add(1, 1, 60.0).unwrap();
+        assert!(dir.join("1.bin").exists());
+        drop(store); // as on an error, without finish
+        assert!(!dir.exists());
+    }
+}
@@ -92,7 +92,7 @@ impl Iterator for TPX3Reader {
 /// time-of-arrival from a "hit" packet; unsafe due to being extremely "hot" code for reading
 /// shift rights after multiplication are in place of division; 25_000 is 25 * 1000; 409600000
 /// is 16384 * 1000 * 25
-fn parse_hit_packet(p: u64) -> (u8, u8, u32, i64) {
+pub(crate) fn parse_hit_packet(p: u64) -> (u8, u8, u32, i64) {
     unsafe {
         let pix = (p & 0x0000_7000_0000_0000).unchecked_shr(44);
         let col = (p & 0x0FE0_0000_0000_0000).unchecked_shr(52).unchecked_add(pix.unchecked_shr(2));
@@ -113,7 +113,7 @@ fn parse_tdc_packet(p: u64) -> (i64, u64) {
     let expansion_time = (p >> 5 & 0xF).wrapping_sub(1) << 9;
     let finetime = expansion_time / 12;
     let trigtime = (p & 0x0000_0000_0000_0E00) | (finetime & 0x0000_0000_0000_01FF);
-    let add_bit = !(expansion_time % 12 == 0 && expansion_time < 1023) as u64;
+    let add_bit = !(expansion_time.is_multiple_of(12) && expansion_time < 1023) as u64;
     let tdc = (coarsetime * 1000 + (trigtime * 1000) / 4096) * 25; // in ps
     ((tdc + add_bit) as i64, trigger_number)
 }
@@ -186,3 +186,135 @@ impl Iterator for TDCReader {
         self.next() // go again and return whatever that call returns
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::Hit;
+    use proptest::prelude::*;
+
+    const TICK: i64 = 1563; // 25_000 / 16 ps, rounded up
+
+    /// builds a time-of-arrival that lies exactly on the hit packet's 1.5625 ns lattice
+    fn lattice_toa(global: i64, coarse: i64, fine: i64) -> i64 {
+        global * 409_600_000 + ((((coarse << 4) | fine) * 25_000) >> 4)
+    }
+
+    /// builds a raw tdc packet the way the camera emits it (fine values run from 1 to 12)
+    fn raw_tdc_packet(trigger: u64, coarse: u64, trigtime: u64, fine: u64) -> u64 {
+        0x6A << 56 | trigger << 44 | coarse << 12 | (trigtime & 0x0E00) | fine << 5
+    }
+
+    fn modular_distance(a: i64, b: i64, modulus: i64) -> i64 {
+        let d = (a - b).rem_euclid(modulus);
+        d.min(modulus - d)
+    }
+
+    proptest! {
+        #[test]
+        fn hit_packet_round_trips_on_lattice(
+            col: u8, row: u8, tot in 0u32..1024, global in 0i64..0x1_0000,
+            coarse in 0i64..0x4000, fine in 0i64..16,
+        ) {
+            let toa = lattice_toa(global, coarse, fine);
+            let packet = Hit::new(0, toa, tot * 25, col, row).to_hit_packet();
+            prop_assert_eq!(packet >> 60, 0xB);
+            prop_assert_eq!(parse_hit_packet(packet), (col, row, tot * 25, toa));
+        }
+
+        #[test]
+        fn hit_packet_toa_is_within_one_tick(toa in 0i64..4 * HIT_LIMIT, col: u8, row: u8) {
+            let packet = Hit::new(0, toa, 0, col, row).to_hit_packet();
+            let (pcol, prow, _, ptoa) = parse_hit_packet(packet);
+            prop_assert_eq!((pcol, prow), (col, row));
+            prop_assert!(modular_distance(ptoa, toa, HIT_LIMIT) < TICK);
+        }
+
+        #[test]
+        fn hit_packet_ignores_rollovers(toa in 0i64..HIT_LIMIT, rolls in 1i64..64) {
+            let hit = Hit::new(0, toa, 0, 7, 9);
+            let rolled = Hit::new(0, toa + rolls * HIT_LIMIT, 0, 7, 9);
+            prop_assert_eq!(hit.to_hit_packet(), rolled.to_hit_packet());
+        }
+
+        #[test]
+        fn hit_packet_tot_is_truncated_to_25_ns(tot in 0u32..25 * 1024) {
+            let (_, _, ptot, _) = parse_hit_packet(Hit::new(0, 0, tot, 0, 0).to_hit_packet());
+            prop_assert_eq!(ptot, tot / 25 * 25);
+        }
+
+        #[test]
+        fn tdc_packet_round_trips_on_lattice(
+            trigger in 0u64..0x1000, coarse in 0u64..0x1_0000_0000, trigtime in 0u64..0x1000,
+            fine in 1u64..=12,
+        ) {
+            let (time, triggers) = parse_tdc_packet(raw_tdc_packet(trigger, coarse, trigtime, fine));
+            prop_assert!(time < TDC_LIMIT);
+            let pulse = Pulse { time, triggers, ..Default::default() };
+            let packet = pulse.to_tdc_packet();
+            prop_assert_eq!(packet >> 60, 0x6);
+            prop_assert_eq!(parse_tdc_packet(packet), (time, trigger));
+        }
+
+        #[test]
+        fn tdc_packet_ignores_rollovers(time in 0i64..TDC_LIMIT, rolls in 1i64..16) {
+            let pulse = Pulse { time, ..Default::default() };
+            let rolled = Pulse { time: time + rolls * TDC_LIMIT, ..Default::default() };
+            prop_assert_eq!(pulse.to_tdc_packet(), rolled.to_tdc_packet());
+        }
+
+        #[test]
+        fn blob_packet_round_trips(
+            tot in 0u32..25 * 1024, size in 2u16.., col_offset: u8, row_offset: u8,
+        ) {
+            let hit = Hit { tot, size, col_offset, row_offset, ..Hit::new(0, 0, tot, 3, 4) };
+            let (_, _, ptot, _) = parse_hit_packet(hit.to_hit_packet());
+            let mut decoded = Hit::new(0, 0, ptot, 3, 4);
+            decoded.update_with_blob_packet(hit.to_blob_packet());
+            prop_assert_eq!(decoded.tot, tot / 25 * 25);
+            prop_assert_eq!(decoded.size, size);
+            prop_assert_eq!((decoded.col_offset, decoded.row_offset), (col_offset, row_offset));
+        }
+
+        #[test]
+        fn blob_packet_round_trips_large_clustered_tot(tot in 0u32..25 << 24, size in 2u16..) {
+            let hit = Hit { tot, size, ..Hit::new(0, 0, tot, 3, 4) };
+            let (_, _, ptot, _) = parse_hit_packet(hit.to_hit_packet());
+            let mut decoded = Hit::new(0, 0, ptot, 3, 4);
+            decoded.update_with_blob_packet(hit.to_blob_packet());
+            prop_assert_eq!(decoded.tot, tot / 25 * 25);
+        }
+
+        #[test]
+        fn legacy_blob_packet_keeps_hit_tot(tot in 0u32..25 * 1024, size in 2u16.., offsets: (u8, u8)) {
+            let legacy = (crate::hit::BLOB_HEADER_V1 as u64) << 56
+                | (offsets.0 as u64) << 48 | (offsets.1 as u64) << 40 | size as u64;
+            let mut decoded = Hit::new(0, 0, tot, 3, 4);
+            decoded.update_with_blob_packet(legacy);
+            prop_assert_eq!((decoded.tot, decoded.size), (tot, size));
+            prop_assert_eq!((decoded.col_offset, decoded.row_offset), offsets);
+        }
+    }
+
+    #[test]
+    fn blob_packet_saturates_summed_tot() {
+        let hit = Hit { size: 2, ..Hit::new(0, 0, u32::MAX, 3, 4) };
+        let mut decoded = Hit::new(0, 0, 0, 3, 4);
+        decoded.update_with_blob_packet(hit.to_blob_packet());
+        assert_eq!(decoded.tot, 0x00FF_FFFF * 25);
+        assert_eq!(parse_hit_packet(hit.to_hit_packet()).2, 0x3FF * 25);
+    }
+
+    #[test]
+    fn hit_packet_carries_into_next_coarse_period() {
+        for global in [0, 1, 0xFFFE, 0xFFFF] {
+            for before in 1..2_000 {
+                let toa = (global + 1) * 409_600_000 - before;
+                let packet = Hit::new(0, toa, 0, 255, 255).to_hit_packet();
+                let (col, row, _, ptoa) = parse_hit_packet(packet);
+                assert_eq!((packet >> 60, col, row), (0xB, 255, 255));
+                assert!(modular_distance(ptoa, toa, HIT_LIMIT) < TICK, "{} -> {}", toa, ptoa);
+            }
+        }
+    }
+}
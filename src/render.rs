@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+
+use crate::image::{Counts, Scaling, FULL_SCALE};
+
+/// false color maps; the matplotlib ones are interpolated from nine of their colors
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Colormap {
+    Gray,
+    Viridis,
+    Inferno,
+    Hot,
+}
+
+const VIRIDIS: [[u8; 3]; 9] = [
+    [68, 1, 84],
+    [71, 45, 123],
+    [59, 82, 139],
+    [44, 114, 142],
+    [33, 145, 140],
+    [40, 174, 128],
+    [94, 201, 98],
+    [173, 220, 48],
+    [253, 231, 37],
+];
+
+const INFERNO: [[u8; 3]; 9] = [
+    [0, 0, 4],
+    [31, 12, 72],
+    [85, 15, 109],
+    [136, 34, 106],
+    [186, 54, 85],
+    [227, 89, 51],
+    [249, 142, 9],
+    [249, 203, 53],
+    [252, 255, 164],
+];
+
+impl Colormap {
+    /// the color of a fraction (0 to 1) of the range
+    pub fn color(&self, fraction: f64) -> [u8; 3] {
+        let f = if fraction.is_nan() { 0.0 } else { fraction.clamp(0.0, 1.0) };
+        let channel = |v: f64| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+        match self {
+            Colormap::Gray => [channel(f); 3],
+            Colormap::Viridis => interpolate(&VIRIDIS, f),
+            Colormap::Inferno => interpolate(&INFERNO, f),
+            // black to red to yellow to white
+            Colormap::Hot => [channel(f / 0.365), channel((f - 0.365) / 0.381), channel((f - 0.746) / 0.254)],
+        }
+    }
+}
+
+fn interpolate(table: &[[u8; 3]], fraction: f64) -> [u8; 3] {
+    let position = fraction * (table.len() - 1) as f64;
+    let i = (position.floor() as usize).min(table.len() - 2);
+    let t = position - i as f64;
+    let mix = |c: usize| (table[i][c] as f64 * (1.0 - t) + table[i + 1][c] as f64 * t).round() as u8;
+    [mix(0), mix(1), mix(2)]
+}
+
+/// three ion images (by their names, e.g., "31.0mz" or a mass list label) combined in the red,
+/// green and blue channels of one image
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Overlay {
+    pub name: String,
+    pub red: Option<String>,
+    pub green: Option<String>,
+    pub blue: Option<String>,
+}
+
+/// the 8 bit color images made next to the 16 bit grayscale ones
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Presentation {
+    pub colormap: Option<Colormap>, // if set, every image is also saved with it
+    pub overlays: Vec<Overlay>,
+    pub scale_bar: bool, // burned into the color images
+    pub label: bool,     // the m/z or label of the image, burned into the color images
+}
+
+impl Default for Presentation {
+    fn default() -> Presentation {
+        Presentation { colormap: None, overlays: vec![], scale_bar: true, label: true }
+    }
+}
+
+/// an 8 bit RGB image, row by row
+#[derive(Clone, Debug, PartialEq)]
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+/// the fraction of the scaling range each count is at
+fn fractions<T: Counts>(buffer: &[T], scaling: Scaling) -> impl Iterator<Item = f64> + '_ {
+    let range = scaling.range(buffer);
+    let full_scale = if scaling == Scaling::Raw { u16::MAX as f64 } else { FULL_SCALE };
+    buffer.iter().map(move |v| scaling.apply(v.to_f64(), range) as f64 / full_scale)
+}
+
+impl RgbImage {
+    pub fn colorize<T: Counts>(buffer: &[T], width: usize, scaling: Scaling, colormap: Colormap) -> RgbImage {
+        let pixels = fractions(buffer, scaling).map(|f| colormap.color(f)).collect();
+        RgbImage { width, height: buffer.len() / width, pixels }
+    }
+
+    /// each channel is scaled on its own; missing channels stay black
+    pub fn overlay<T: Counts>(
+        channels: [Option<&[T]>; 3], width: usize, height: usize, scaling: Scaling,
+    ) -> RgbImage {
+        let mut pixels = vec![[0u8; 3]; width * height];
+        for (c, buffer) in channels.iter().enumerate() {
+            if let Some(buffer) = buffer {
+                for (pixel, f) in pixels.iter_mut().zip(fractions(buffer, scaling)) {
+                    pixel[c] = (f * 255.0).round() as u8;
+                }
+            }
+        }
+        RgbImage { width, height, pixels }
+    }
+
+    fn fill(&mut self, x: usize, y: usize, w: usize, h: usize, color: [u8; 3]) {
+        for row in y..(y + h).min(self.height) {
+            for col in x..(x + w).min(self.width) {
+                self.pixels[col + row * self.width] = color;
+            }
+        }
+    }
+
+    /// the size of a font pixel, so that text stays legible on large images
+    fn font_scale(&self) -> usize { (self.height.min(self.width) / 150).max(1) }
+
+    /// draws text with its top left corner at (x, y), with a dark shadow for contrast
+    pub fn draw_text(&mut self, text: &str, x: usize, y: usize) {
+        let scale = self.font_scale();
+        for (shadow, color) in [(scale, [0, 0, 0]), (0, [255, 255, 255])] {
+            for (i, c) in text.chars().enumerate() {
+                for (row, bits) in glyph(c).iter().enumerate() {
+                    for col in (0..5).filter(|col| bits >> (4 - col) & 1 == 1) {
+                        let (px, py) = (x + (i * 6 + col) * scale + shadow, y + row * scale + shadow);
+                        self.fill(px, py, scale, scale, color);
+                    }
+                }
+            }
+        }
+    }
+
+    /// the m/z (or label) in the top left corner
+    pub fn draw_label(&mut self, label: &str) {
+        let margin = 3 * self.font_scale();
+        self.draw_text(label, margin, margin);
+    }
+
+    /// a bar of a round length (1, 2 or 5 times a power of ten micrometres) near a fifth of the
+    /// image width in the bottom left corner, with its length above it
+    pub fn draw_scale_bar(&mut self, pixels_per_mm: f64) {
+        let scale = self.font_scale();
+        let target = self.width as f64 / 5.0 / pixels_per_mm * 1000.0; // in um
+        let decade = 10f64.powf(target.log10().floor());
+        let length = [5.0, 2.0, 1.0].iter().map(|m| m * decade).find(|&l| l <= target).unwrap_or(decade);
+        let bar = (length / 1000.0 * pixels_per_mm).round() as usize;
+        let (margin, thickness) = (3 * scale, 2 * scale);
+        let y = self.height.saturating_sub(margin + thickness);
+        self.fill(margin + scale, y + scale, bar, thickness, [0, 0, 0]);
+        self.fill(margin, y, bar, thickness, [255, 255, 255]);
+        let text = match length >= 1000.0 {
+            true => format!("{} mm", length / 1000.0),
+            false => format!("{} µm", length),
+        };
+        self.draw_text(&text, margin, y.saturating_sub(10 * scale));
+    }
+}
+
+/// 5x7 glyphs, one row per byte (the low five bits, left to right); lowercase letters are drawn
+/// as capitals except for the m, z and µ of units
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        'm' => [0x00, 0x00, 0x1A, 0x15, 0x15, 0x11, 0x11],
+        'z' => [0x00, 0x00, 0x1F, 0x02, 0x04, 0x08, 0x1F],
+        'µ' => [0x00, 0x11, 0x11, 0x11, 0x13, 0x1D, 0x10],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        ' ' => [0x00; 7],
+        c if c.is_ascii_lowercase() => glyph(c.to_ascii_uppercase()),
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // ?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colormaps_span_their_tables() {
+        assert_eq!(Colormap::Viridis.color(0.0), VIRIDIS[0]);
+        assert_eq!(Colormap::Viridis.color(1.0), VIRIDIS[8]);
+        assert_eq!(Colormap::Inferno.color(0.5), INFERNO[4]);
+        assert_eq!(Colormap::Inferno.color(1.0 / 16.0), [16, 6, 38]);
+        assert_eq!((Colormap::Hot.color(0.0), Colormap::Hot.color(1.0)), ([0, 0, 0], [255, 255, 255]));
+        assert_eq!(Colormap::Gray.color(f64::NAN), [0, 0, 0]);
+    }
+
+    #[test]
+    fn overlays_fill_the_channels() {
+        let (red, blue): (Vec<u32>, Vec<u32>) = (vec![0, 10, 5, 0], vec![2, 0, 0, 2]);
+        let image = RgbImage::overlay([Some(&red[..]), None, Some(&blue[..])], 2, 2, Scaling::Linear);
+        assert_eq!(image.pixels, vec![[0, 0, 255], [255, 0, 0], [128, 0, 0], [0, 0, 255]]);
+    }
+
+    #[test]
+    fn annotations_are_drawn_inside_the_image() {
+        let mut image = RgbImage::colorize(&vec![0u16; 200 * 100], 200, Scaling::Linear, Colormap::Gray);
+        image.draw_label("31.0 m/z");
+        image.draw_scale_bar(200.0); // 1 mm is 200 pixels, so the bar is 200 um (40 pixels) long
+        let white = |x: usize, y: usize| image.pixels[x + y * image.width] == [255, 255, 255];
+        assert!(white(3, 3)); // top left of the "3"
+        assert!(white(3, 95) && white(42, 95) && !white(43, 95));
+        assert_eq!(image.pixels.len(), 200 * 100);
+    }
+}
@@ -0,0 +1,130 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+/// the outline of a region of interest, in image pixels (col, row) of the total ion count image;
+/// a pixel is inside when its center is
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Shape {
+    Rectangle { col: f64, row: f64, width: f64, height: f64 },
+    Polygon { vertices: Vec<(f64, f64)> },
+    Mask { path: std::path::PathBuf }, // a png of the image size, non-zero (non-black) pixels are inside
+}
+
+/// a named region of interest
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Roi {
+    pub name: String,
+    pub shape: Shape,
+}
+
+/// the most regions of interest that can be accumulated in one pass
+pub const MAX_ROIS: usize = 64;
+
+impl Roi {
+    /// which pixels of a cols x rows image are inside, row by row
+    pub fn rasterize(&self, cols: usize, rows: usize) -> Result<Vec<bool>, Box<dyn Error>> {
+        let centers = (0..rows).flat_map(|r| (0..cols).map(move |c| (c as f64 + 0.5, r as f64 + 0.5)));
+        match &self.shape {
+            Shape::Rectangle { col, row, width, height } => Ok(centers
+                .map(|(x, y)| x >= *col && x < col + width && y >= *row && y < row + height)
+                .collect()),
+            Shape::Polygon { vertices } => Ok(centers.map(|(x, y)| inside_polygon(vertices, x, y)).collect()),
+            Shape::Mask { path } => read_mask(path, cols, rows),
+        }
+    }
+}
+
+/// combines the regions into one bit set per pixel (bit i for region i)
+pub fn rasterize_all(rois: &[Roi], cols: usize, rows: usize) -> Result<Vec<u64>, Box<dyn Error>> {
+    if rois.len() > MAX_ROIS {
+        return Err(format!("{} regions of interest, at most {} are supported", rois.len(), MAX_ROIS).into());
+    }
+    let mut membership = vec![0u64; cols * rows];
+    for (i, roi) in rois.iter().enumerate() {
+        for (bits, inside) in membership.iter_mut().zip(roi.rasterize(cols, rows)?) {
+            *bits |= (inside as u64) << i;
+        }
+    }
+    Ok(membership)
+}
+
+/// even-odd rule, so self-intersecting outlines alternate between inside and outside
+fn inside_polygon(vertices: &[(f64, f64)], x: f64, y: f64) -> bool {
+    let mut inside = false;
+    let mut previous = match vertices.last() {
+        Some(&v) => v,
+        None => return false,
+    };
+    for &(vx, vy) in vertices {
+        let (px, py) = previous;
+        if (vy > y) != (py > y) && x < vx + (y - vy) * (px - vx) / (py - vy) {
+            inside = !inside;
+        }
+        previous = (vx, vy);
+    }
+    inside
+}
+
+fn read_mask(path: &std::path::Path, cols: usize, rows: usize) -> Result<Vec<bool>, Box<dyn Error>> {
+    let mut decoder = png::Decoder::new(std::fs::File::open(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND); // palettes and low bit depths to 8 bit
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    if (info.width as usize, info.height as usize) != (cols, rows) {
+        return Err(format!("mask {:?} is {}x{}, the image {}x{}", path, info.width, info.height, cols, rows).into());
+    }
+    let bytes = if info.bit_depth == png::BitDepth::Sixteen { 2 } else { 1 };
+    let samples = info.color_type.samples();
+    let colors = match info.color_type {
+        png::ColorType::GrayscaleAlpha | png::ColorType::Rgba => samples - 1, // transparency is ignored
+        _ => samples,
+    };
+    let pixels = buf[..info.buffer_size()].chunks(info.line_size).flat_map(|line| line.chunks(samples * bytes));
+    Ok(pixels.map(|pixel| pixel[..colors * bytes].iter().any(|&b| b > 0)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roi(shape: Shape) -> Roi { Roi { name: String::from("test"), shape } }
+
+    fn inside(mask: &[bool], cols: usize) -> Vec<(usize, usize)> {
+        mask.iter().enumerate().filter(|(_, &m)| m).map(|(i, _)| (i % cols, i / cols)).collect()
+    }
+
+    #[test]
+    fn rectangles_and_polygons_contain_pixel_centers() {
+        let rectangle = roi(Shape::Rectangle { col: 1.0, row: 2.0, width: 2.0, height: 2.0 });
+        assert_eq!(inside(&rectangle.rasterize(5, 5).unwrap(), 5), vec![(1, 2), (2, 2), (1, 3), (2, 3)]);
+        let triangle = roi(Shape::Polygon { vertices: vec![(0.0, 0.0), (4.0, 0.0), (0.0, 4.0)] });
+        let expected = vec![(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (0, 2)]; // the hypotenuse is outside
+        assert_eq!(inside(&triangle.rasterize(5, 5).unwrap(), 5), expected);
+        assert!(!roi(Shape::Polygon { vertices: vec![] }).rasterize(2, 2).unwrap().contains(&true));
+    }
+
+    #[test]
+    fn masks_are_read_from_pngs_of_the_image_size() {
+        let path = std::env::temp_dir().join(format!("two_grids_roi_mask_{}.png", std::process::id()));
+        let mut pixels = vec![0u16; 6 * 4];
+        pixels[7] = 1;
+        pixels[22] = 900;
+        crate::writer::save_png(&pixels, 6, 4, &path).unwrap();
+        let mask = roi(Shape::Mask { path: path.clone() });
+        assert_eq!(inside(&mask.rasterize(6, 4).unwrap(), 6), vec![(1, 1), (4, 3)]);
+        assert!(mask.rasterize(4, 6).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn regions_combine_into_bit_sets() {
+        let rois = vec![
+            roi(Shape::Rectangle { col: 0.0, row: 0.0, width: 2.0, height: 1.0 }),
+            roi(Shape::Rectangle { col: 1.0, row: 0.0, width: 2.0, height: 1.0 }),
+        ];
+        assert_eq!(rasterize_all(&rois, 4, 1).unwrap(), vec![0b01, 0b11, 0b10, 0b00]);
+        assert!(rasterize_all(&vec![rois[0].clone(); MAX_ROIS + 1], 4, 1).is_err());
+    }
+}
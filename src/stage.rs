@@ -1,4 +1,8 @@
-#[derive(Copy, Clone, PartialEq, Eq)]
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     Up,
     Down,
@@ -27,3 +31,269 @@ pub struct Coord {
 impl Coord {
     pub fn is_not_inf(&self) -> bool { self.x != f64::INFINITY && self.y != f64::INFINITY }
 }
+
+/// the pulses are in separate passes when more than this (ps) apart, as in the inferred coordinates
+const PASS_GAP: i64 = 30_000_000_000;
+
+/// a position log of the stage controller, read instead of inferring the coordinates from the
+/// timing of the pulses: one "time, x, y" line per sample, in seconds and mm
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StageLog {
+    pub path: std::path::PathBuf,
+    #[serde(default)]
+    pub origin: Option<(f64, f64)>, // stage position (mm) of the image's 0, 0; if unset, the lowest of the passes
+    #[serde(default)]
+    pub first_pass: usize, // the pass of the log the pulses start in, if the log was started earlier
+}
+
+impl StageLog {
+    /// the coordinates of every pulse (TDC times in ps): the passes of the log are aligned with
+    /// those of the pulses, and the position is interpolated between the samples around a pulse;
+    /// pulses outside of the log get infinite coordinates, so they are left out of the images
+    pub fn coordinates(&self, tdcs: &[i64]) -> Result<Vec<Coord>, Box<dyn Error>> {
+        let samples = read_stage_log(&self.path)?;
+        let passes = find_passes(&samples);
+        let alignment = align(&samples, passes.get(self.first_pass..).unwrap_or(&[]), &pulse_passes(tdcs))?;
+        println!(
+            "stage log: {} passes, offset {:.3} s, drift {:+.1} ppm, {:.2} ms rms",
+            passes.len(),
+            alignment.offset / 1e12,
+            alignment.drift_ppm(),
+            alignment.rms / 1e9
+        );
+        let origin = self.origin.unwrap_or_else(|| {
+            let in_passes = passes.iter().flat_map(|p| &samples[p.start..=p.end]);
+            in_passes.fold((f64::INFINITY, f64::INFINITY), |(x, y), s| (x.min(s.x), y.min(s.y)))
+        });
+        Ok(interpolate(&samples, &passes, &alignment, tdcs, origin))
+    }
+}
+
+/// a position of the stage log, time in seconds and x, y in mm
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub time: f64,
+    pub x: f64,
+    pub y: f64,
+}
+
+/// the samples (first and last, inclusive) of a pass of the stage along x
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pass {
+    pub start: usize,
+    pub end: usize,
+    pub direction: Direction,
+}
+
+/// the conversion of the times of the stage log to TDC times: tdc = offset + scale * time
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Alignment {
+    pub offset: f64, // in ps
+    pub scale: f64,  // ps per second of the log
+    pub rms: f64,    // of the pass starts and ends, in ps
+}
+
+impl Alignment {
+    /// how much faster the clock of the stage controller runs than the TDC
+    pub fn drift_ppm(&self) -> f64 { (self.scale / 1e12 - 1.0) * 1e6 }
+
+    pub fn to_log_time(&self, tdc: i64) -> f64 { (tdc as f64 - self.offset) / self.scale }
+}
+
+/// reads a stage log: one "time, x, y" line per sample (seconds, mm) in time order; empty lines,
+/// lines starting with '#' and a header line are skipped
+pub fn read_stage_log(path: &std::path::Path) -> Result<Vec<Sample>, Box<dyn Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut samples: Vec<Sample> = vec![];
+    for (number, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('#')) {
+        let error = || format!("{:?} line {}: expected \"time, x, y\" in time order", path, number + 1);
+        let fields: Vec<Option<f64>> = line.split(',').map(|f| f.trim().parse().ok()).collect();
+        match fields[..] {
+            [Some(time), Some(x), Some(y)] if samples.last().is_none_or(|s| s.time < time) => {
+                samples.push(Sample { time, x, y })
+            }
+            [None, None, None] if samples.is_empty() => (), // the header
+            _ => return Err(error().into()),
+        }
+    }
+    Ok(samples)
+}
+
+/// the passes of the stage: the runs of samples moving along x in one direction (more than along
+/// y) that cover at least half of the longest run; the moves between them are left out, and
+/// repeated samples (the stage did not move) neither end nor extend a run
+pub fn find_passes(samples: &[Sample]) -> Vec<Pass> {
+    let still = |i: usize| samples[i + 1].x == samples[i].x && samples[i + 1].y == samples[i].y;
+    let step = |i: usize| {
+        let (dx, dy) = (samples[i + 1].x - samples[i].x, samples[i + 1].y - samples[i].y);
+        match dx.abs() > dy.abs() {
+            true if dx > 0.0 => Some(Direction::Right),
+            true => Some(Direction::Left),
+            false => None,
+        }
+    };
+    let mut runs: Vec<Pass> = vec![];
+    let mut open = false; // whether the last step that moved was along x
+    for i in (0..samples.len().saturating_sub(1)).filter(|&i| !still(i)) {
+        match (step(i), runs.last_mut()) {
+            (Some(direction), Some(run)) if open && run.direction == direction => run.end = i + 1,
+            (Some(direction), _) => runs.push(Pass { start: i, end: i + 1, direction }),
+            (None, _) => (),
+        }
+        open = step(i).is_some();
+    }
+    let travel = |p: &Pass| (samples[p.end].x - samples[p.start].x).abs();
+    let longest = runs.iter().map(travel).fold(0.0, f64::max);
+    runs.into_iter().filter(|p| travel(p) >= longest / 2.0).collect()
+}
+
+/// the first and last TDC time of each pass of the pulses
+pub fn pulse_passes(tdcs: &[i64]) -> Vec<(i64, i64)> {
+    let mut passes: Vec<(i64, i64)> = vec![];
+    for &tdc in tdcs {
+        match passes.last_mut() {
+            Some(pass) if tdc - pass.1 <= PASS_GAP => pass.1 = tdc,
+            _ => passes.push((tdc, tdc)),
+        }
+    }
+    passes
+}
+
+/// fits the offset and the clock drift of the log by least squares on the starts and ends of the
+/// passes, the first of the log with the first of the pulses and so on (passes the log has in
+/// excess at the end are ignored); evenly timed passes fit as well shifted by a pass, so which
+/// pass the pulses start in has to be known
+pub fn align(samples: &[Sample], passes: &[Pass], pulse_passes: &[(i64, i64)]) -> Result<Alignment, Box<dyn Error>> {
+    if pulse_passes.is_empty() || passes.len() < pulse_passes.len() {
+        return Err(format!("the stage log has {} passes, the pulses {}", passes.len(), pulse_passes.len()).into());
+    }
+    let points: Vec<(f64, f64)> = passes
+        .iter()
+        .zip(pulse_passes)
+        .flat_map(|(p, &(first, last))| vec![(samples[p.start].time, first as f64), (samples[p.end].time, last as f64)])
+        .collect();
+    let n = points.len() as f64;
+    let (mt, mc) = points.iter().fold((0.0, 0.0), |(t, c), p| (t + p.0 / n, c + p.1 / n));
+    let stt: f64 = points.iter().map(|p| (p.0 - mt).powi(2)).sum();
+    let stc: f64 = points.iter().map(|p| (p.0 - mt) * (p.1 - mc)).sum();
+    let (scale, offset) = (stc / stt, mc - stc / stt * mt);
+    if !scale.is_finite() || scale <= 0.0 {
+        return Err("the passes of the stage log do not line up with those of the pulses".into());
+    }
+    let rms = (points.iter().map(|p| (offset + scale * p.0 - p.1).powi(2)).sum::<f64>() / n).sqrt();
+    Ok(Alignment { offset, scale, rms })
+}
+
+/// the position at every pulse relative to the origin, moving in the direction of the last pass
+/// started
+pub fn interpolate(
+    samples: &[Sample], passes: &[Pass], alignment: &Alignment, tdcs: &[i64], origin: (f64, f64),
+) -> Vec<Coord> {
+    let outside = Coord { x: f64::INFINITY, y: f64::INFINITY, direction: Direction::Right };
+    tdcs.iter()
+        .map(|&tdc| {
+            let time = alignment.to_log_time(tdc);
+            let i = samples.partition_point(|s| s.time <= time);
+            if i == 0 || i == samples.len() {
+                return outside;
+            }
+            let (a, b) = (samples[i - 1], samples[i]);
+            let fraction = (time - a.time) / (b.time - a.time);
+            let pass = passes.iter().take_while(|p| p.start < i).last().or_else(|| passes.first());
+            Coord {
+                x: a.x + fraction * (b.x - a.x) - origin.0,
+                y: a.y + fraction * (b.y - a.y) - origin.1,
+                direction: pass.map_or(Direction::Right, |p| p.direction),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// three serpentine passes of 4 mm at 1 mm/s, 0.5 mm apart, sampled every 0.1 s and starting
+    /// after a second at the stage position (10, 20)
+    fn serpentine() -> Vec<Sample> {
+        let mut samples = vec![Sample { time: 0.0, x: 10.0, y: 20.0 }];
+        let mut time = 1.0;
+        for pass in 0..3 {
+            let y = 20.0 + 0.5 * pass as f64;
+            for i in 0..=40 {
+                let x = if pass % 2 == 0 { 10.0 + 0.1 * i as f64 } else { 14.0 - 0.1 * i as f64 };
+                samples.push(Sample { time, x, y });
+                time += 0.1;
+            }
+            time += 39.9; // the move to the next pass
+        }
+        samples
+    }
+
+    #[test]
+    fn repeated_samples_do_not_split_passes() {
+        let mut samples = vec![];
+        for s in serpentine() {
+            samples.push(s);
+            if (s.x - 12.0).abs() < 1e-9 || s.x == 10.0 {
+                // logged again before the stage moved on, in the middle and at the ends of passes
+                samples.push(Sample { time: s.time + 0.05, ..s });
+            }
+        }
+        let passes = find_passes(&samples);
+        assert_eq!(passes.len(), 3);
+        for pass in passes.iter() {
+            let (start, end) = (samples[pass.start], samples[pass.end]);
+            assert!((end.x - start.x).abs() > 3.99, "{:?}", pass);
+            assert!(samples[pass.start + 1].x != start.x && samples[pass.end - 1].x != end.x, "{:?}", pass);
+        }
+    }
+
+    #[test]
+    fn logs_are_read_with_a_header_and_in_time_order() {
+        let path = std::env::temp_dir().join(format!("two_grids_stage_log_{}.csv", std::process::id()));
+        std::fs::write(&path, "time,x,y\n# comment\n0.0, 1.0, 2.0\n\n0.5, 1.5, 2.0\n").unwrap();
+        let expected = vec![Sample { time: 0.0, x: 1.0, y: 2.0 }, Sample { time: 0.5, x: 1.5, y: 2.0 }];
+        assert_eq!(read_stage_log(&path).unwrap(), expected);
+        std::fs::write(&path, "0.5, 1.0, 2.0\n0.0, 1.5, 2.0\n").unwrap();
+        assert!(read_stage_log(&path).is_err()); // out of order
+        std::fs::write(&path, "0.0, 1.0\n").unwrap();
+        assert!(read_stage_log(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pulses_are_aligned_with_offset_and_drift_and_interpolated() {
+        let samples = serpentine();
+        let passes = find_passes(&samples);
+        let directions: Vec<Direction> = passes.iter().map(|p| p.direction).collect();
+        assert_eq!(directions, [Direction::Right, Direction::Left, Direction::Right]);
+        assert_eq!((passes[0].start, passes[0].end), (1, 41));
+
+        // the TDC starts 5 s before the log and its clock is 50 ppm slower; a pulse every 10 ms of
+        // the passes, except for the first, which the camera missed
+        let (offset, scale) = (-5e12, 1e12 * (1.0 - 50e-6));
+        let to_tdc = |time: f64| (offset + scale * time).round() as i64;
+        let tdcs: Vec<i64> = passes[1..]
+            .iter()
+            .flat_map(|p| {
+                let start = samples[p.start].time;
+                (0..=400).map(move |i| start + 0.01 * i as f64)
+            })
+            .map(to_tdc)
+            .collect();
+        assert!(align(&samples, &passes[2..], &pulse_passes(&tdcs)).is_err()); // only one pass left
+        let alignment = align(&samples, &passes[1..], &pulse_passes(&tdcs)).unwrap();
+        assert!((alignment.offset - offset).abs() < 1e3, "{:?}", alignment);
+        assert!((alignment.drift_ppm() + 50.0).abs() < 1e-3, "{:?}", alignment);
+
+        let coords = interpolate(&samples, &passes, &alignment, &tdcs, (10.0, 20.0));
+        assert_eq!(coords.len(), tdcs.len());
+        let (first, middle, last) = (coords[0], coords[200], coords[402]); // 401 is on the start
+        assert!((first.x - 4.0).abs() < 1e-6 && (first.y - 0.5).abs() < 1e-6 && first.direction == Direction::Left);
+        assert!((middle.x - 2.0).abs() < 1e-6 && middle.direction == Direction::Left);
+        assert!((last.x - 0.01).abs() < 1e-6 && (last.y - 1.0).abs() < 1e-6 && last.direction == Direction::Right);
+        let before = interpolate(&samples, &passes, &alignment, &[to_tdc(-1.0)], (10.0, 20.0));
+        assert!(!before[0].is_not_inf());
+    }
+}
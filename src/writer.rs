@@ -1,9 +1,9 @@
 use std::{
     error::Error,
-    io::{BufWriter, Write},
+    io::{BufReader, BufWriter, Read, Write},
 };
 
-use crate::{image, mass, pulse, reader};
+use crate::{hit, image, imzml_reader, mass, pulse, reader, render, roi};
 use itertools::Itertools;
 use plotly::{
     common::{Mode, Title},
@@ -28,45 +28,207 @@ pub fn centroid_cluster_compress(path: &std::path::Path) -> Result<(), Box<dyn E
     Ok(())
 }
 
-/// saves a buffer to a png with a width and a height (h) at a path
-pub fn save_png(buf: &[u16], w: u32, h: u32, path: &std::path::Path) -> Result<(), Box<dyn Error>> {
-    let (max, min) = (*buf.iter().max().unwrap() as f64, *buf.iter().min().unwrap() as f64);
-    println!("saving png: maximum pixel value {} {:?}", max, &path);
-    let data: Vec<u8> =
-        buf.iter().flat_map(|i| ((((*i as f64) / max) * 65530.0) as u16).to_be_bytes()).collect();
+/// rewrites a .tpx3c file with version 1 blob packets as `<name>_v2.tpx3c`; all other packets are
+/// copied unchanged, returns the number of migrated blob packets
+pub fn migrate_tpx3c(path: &std::path::Path) -> Result<usize, Box<dyn Error>> {
+    let base_name = path.file_stem().ok_or("no file name")?.to_str().ok_or("no file name")?;
+    let mut input = BufReader::new(std::fs::File::open(path)?);
+    let out = std::fs::File::create(path.with_file_name(base_name.to_owned() + "_v2.tpx3c"))?;
+    let mut output = BufWriter::new(out);
+    let (mut bytes, mut last_hit, mut migrated) = ([0u8; 8], hit::Hit::default(), 0);
+    loop {
+        match input.read_exact(&mut bytes) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        let mut packet = u64::from_le_bytes(bytes);
+        if packet >> 60 == 0xB {
+            let (col, row, tot, toa) = reader::parse_hit_packet(packet);
+            last_hit = hit::Hit::new(0, toa, tot, col, row);
+        } else if (packet >> 56) as u8 == hit::BLOB_HEADER_V1 {
+            last_hit.update_with_blob_packet(packet);
+            packet = last_hit.to_blob_packet();
+            migrated += 1;
+        }
+        output.write_all(&packet.to_le_bytes())?;
+    }
+    output.flush()?;
+    println!("migrated {} blob packets", migrated);
+    Ok(migrated)
+}
+
+/// writes the matched references of an automatic calibration with their residuals as a csv
+pub fn save_calibration_report(report: &mass::CalibrationReport, path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(std::fs::File::create(path)?);
+    writeln!(file, "# calibration: {:?}", report.calibration)?;
+    writeln!(file, "reference_mz,tof_ps,calibrated_mz,error_mda,error_ppm,rejected")?;
+    for m in report.matches.iter() {
+        let (mda, ppm) = (m.error_mda(), m.error_ppm());
+        writeln!(file, "{},{},{:.5},{:.3},{:.1},{}", m.reference, m.tof, m.mz, mda, ppm, m.rejected)?;
+    }
+    Ok(())
+}
+
+/// writes how the images regenerated from an imzML file differ from those made from the events
+pub fn save_validation_report(
+    comparisons: &[imzml_reader::Comparison], path: &std::path::Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(std::fs::File::create(path)?);
+    writeln!(file, "image,expected_counts,imzml_counts,differing_pixels,max_difference")?;
+    for c in comparisons.iter() {
+        writeln!(file, "{},{},{},{},{}", c.name, c.expected, c.found, c.differing_pixels, c.max_difference)?;
+    }
+    Ok(())
+}
+
+/// writes the peak table both as a csv and as a json file (the extension of the path is replaced)
+pub fn save_peak_table(peaks: &[mass::PeakSummary], path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(std::fs::File::create(path.with_extension("csv"))?);
+    writeln!(file, "tof_ps,mz,fwhm_ns,fwhm_da,resolving_power,area,snr,pixels")?;
+    for p in peaks.iter() {
+        writeln!(
+            file,
+            "{},{:.4},{:.3},{:.5},{:.1},{:.1},{:.1},{}",
+            p.tof, p.mz, p.fwhm_ns, p.fwhm_da, p.resolving_power, p.area, p.snr, p.pixels
+        )?;
+    }
+    std::fs::write(path.with_extension("json"), serde_json::to_string_pretty(peaks)?)?;
+    Ok(())
+}
+
+/// escapes the characters that cannot appear in xml attributes
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// saves a buffer to a png with a width and a height (h) at a path, scaled linearly to its maximum
+pub fn save_png<T: image::Counts>(buf: &[T], w: u32, h: u32, path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    save_scaled_png(buf, w, h, path, image::Scaling::Linear)
+}
+
+/// saves a buffer to a 16 bit grayscale png; the scaling and the range of counts it maps to zero
+/// and full scale are recorded in text chunks, and pixels clipped by either the accumulator or the
+/// range are reported
+pub fn save_scaled_png<T: image::Counts>(
+    buf: &[T], w: u32, h: u32, path: &std::path::Path, scaling: image::Scaling,
+) -> Result<(), Box<dyn Error>> {
+    let range = scaling.range(buf);
+    println!("saving png: {:?} scaling of {} to {} counts {:?}", scaling, range.0, range.1, path);
+    let saturated = image::saturated(buf);
+    if saturated > 0 {
+        println!("{:?}: {} pixels were clipped at the accumulator maximum", path, saturated);
+    }
+    let above = buf.iter().filter(|v| v.to_f64() > range.1).count();
+    if above > 0 {
+        println!("{:?}: {} pixels are above the scaling range", path, above);
+    }
+    let data: Vec<u8> = buf.iter().flat_map(|v| scaling.apply(v.to_f64(), range).to_be_bytes()).collect();
     let buf_writer = &mut BufWriter::new(std::fs::File::create(path.with_extension("png"))?);
     let mut encoder = png::Encoder::new(buf_writer, w, h);
     encoder.set_color(png::ColorType::Grayscale);
     encoder.set_depth(png::BitDepth::Sixteen);
+    encoder.add_text_chunk("scaling".to_string(), format!("{:?}", scaling))?;
+    encoder.add_text_chunk("range".to_string(), format!("{} {}", range.0, range.1))?;
+    let full_scale = if scaling == image::Scaling::Raw { u16::MAX as f64 } else { image::FULL_SCALE };
+    encoder.add_text_chunk("full_scale".to_string(), full_scale.to_string())?;
     let mut writer = encoder.write_header()?;
     writer.write_image_data(&data)?;
     Ok(())
 }
 
-pub fn plotly_spectra(path: &std::path::Path, tof_len: Option<i64>) -> Result<(), Box<dyn Error>> {
+/// saves an 8 bit RGB image to a png, with the given text chunks (e.g., the scaling used)
+pub fn save_rgb_png(
+    image: &render::RgbImage, path: &std::path::Path, text: &[(&str, String)],
+) -> Result<(), Box<dyn Error>> {
+    let data: Vec<u8> = image.pixels.iter().flatten().copied().collect();
+    let buf_writer = &mut BufWriter::new(std::fs::File::create(path.with_extension("png"))?);
+    let mut encoder = png::Encoder::new(buf_writer, image.width as u32, image.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    for (keyword, value) in text {
+        encoder.add_text_chunk(keyword.to_string(), value.clone())?;
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+/// plots the spectrum to an html report and writes its occupied bins to a csv
+pub fn plotly_spectra(path: &std::path::Path, spectrum: &mass::Spectrum) -> Result<(), Box<dyn Error>> {
     let base_name = path.file_stem().unwrap().to_str().unwrap();
     let spectrum_file = path.with_file_name(base_name.to_owned() + "_report_spectrum.html");
     let mut plot = Plot::new();
+    let x_title = match spectrum.binning {
+        mass::Binning::Time { .. } => "Time (ps)",
+        mass::Binning::Mass { .. } => "m/z",
+    };
     let layout = Layout::new()
-        .x_axis(Axis::new().title(Title::new("Time (ns)")))
+        .x_axis(Axis::new().title(Title::new(x_title)))
         .y_axis(Axis::new().title(Title::new("Pixels activated")));
     plot.set_layout(layout);
-    // this is backwards -> TODO: we should pass the data to this function
-    let (time_axis, intensity_axis) = mass::spectrum(path, tof_len)?;
-    let (time_axis, intensity_axis) = mass::zero_pad(&time_axis, &intensity_axis);
-    let trace1 = Scatter::new(time_axis.clone(), intensity_axis.clone())
+    let (axis, intensity_axis) = spectrum.zero_padded();
+    let trace1 = Scatter::new(axis.clone(), intensity_axis.clone())
         .name("Full spectrum")
         .mode(Mode::Lines);
     plot.add_trace(trace1);
     let full_csv_file = path.with_file_name(base_name.to_owned() + "_report_full_spectrum.csv");
-    let csv_strings: Vec<String> =
-        time_axis.iter().zip(&intensity_axis).map(|(t, i)| format!("{},{}", t, i)).collect();
+    let csv_strings: Vec<String> = match spectrum.binning {
+        mass::Binning::Time { .. } => {
+            axis.iter().zip(&intensity_axis).map(|(t, i)| format!("{},{}", t.round() as i64, i)).collect()
+        }
+        mass::Binning::Mass { .. } => axis.iter().zip(&intensity_axis).map(|(m, i)| format!("{:.5},{}", m, i)).collect(),
+    };
     let mut file = std::fs::File::create(full_csv_file).unwrap(); // scope / file dropped at end of fn
     writeln!(file, "{}", csv_strings.join("\n")).unwrap();
     plot.to_html(spectrum_file);
     Ok(())
 }
 
+/// plots the spectra of the regions of interest on top of each other to an html report and writes
+/// them side by side to a csv (bins with counts in any region and their neighbours)
+pub fn plotly_roi_spectra(
+    path: &std::path::Path, rois: &[roi::Roi], spectra: &[mass::Spectrum],
+) -> Result<(), Box<dyn Error>> {
+    let base_name = path.file_stem().unwrap().to_str().unwrap();
+    let (first, len) = match spectra.first() {
+        Some(first) => (first, spectra.iter().map(|s| s.counts.len()).max().unwrap_or(0)),
+        None => return Ok(()),
+    };
+    let (x_title, x_column) = match first.binning {
+        mass::Binning::Time { .. } => ("Time (ps)", "tof_ps"),
+        mass::Binning::Mass { .. } => ("m/z", "mz"),
+    };
+    let mut plot = Plot::new();
+    plot.set_layout(
+        Layout::new()
+            .x_axis(Axis::new().title(Title::new(x_title)))
+            .y_axis(Axis::new().title(Title::new("Pixels activated"))),
+    );
+    let count = |s: &mass::Spectrum, i: usize| s.counts.get(i).copied().unwrap_or(0);
+    let occupied: Vec<usize> = (0..len)
+        .filter(|&i| (i.saturating_sub(1)..(i + 2)).any(|j| spectra.iter().any(|s| count(s, j) > 0)))
+        .collect();
+    let axis: Vec<f64> = occupied.iter().map(|&i| first.start + i as f64 * first.width()).collect();
+    for (roi, spectrum) in rois.iter().zip(spectra) {
+        let counts: Vec<u32> = occupied.iter().map(|&i| count(spectrum, i)).collect();
+        plot.add_trace(Scatter::new(axis.clone(), counts).name(&roi.name).mode(Mode::Lines));
+    }
+    plot.to_html(path.with_file_name(base_name.to_owned() + "_report_roi_spectra.html"));
+    let mut file = BufWriter::new(std::fs::File::create(
+        path.with_file_name(base_name.to_owned() + "_report_roi_spectra.csv"),
+    )?);
+    let names: Vec<&str> = rois.iter().map(|r| r.name.as_str()).collect();
+    writeln!(file, "{},{}", x_column, names.join(","))?;
+    for (&i, x) in occupied.iter().zip(&axis) {
+        let counts: Vec<String> = spectra.iter().map(|s| count(s, i).to_string()).collect();
+        match first.binning {
+            mass::Binning::Time { .. } => writeln!(file, "{},{}", x.round() as i64, counts.join(","))?,
+            mass::Binning::Mass { .. } => writeln!(file, "{:.5},{}", x, counts.join(","))?,
+        }
+    }
+    Ok(())
+}
+
 pub fn save_masking_image(path: &std::path::Path) -> Result<(), Box<dyn Error>> {
     let image = image::Image {
         tpx3_path: path.to_path_buf(),
@@ -91,3 +253,106 @@ pub fn save_masking_image(path: &std::path::Path) -> Result<(), Box<dyn Error>>
     writer.write_image_data(&data).unwrap();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::Hit;
+
+    /// a raw (uncentroided) pulse with a few spatially separated clusters of adjacent pixels
+    fn raw_pulse(i: i64) -> pulse::Pulse {
+        let time = 1_000_000_000 + i * 56_675_000; // tdcs on the 25 ns coarse clock
+        let mut pulse = pulse::Pulse { time, triggers: (i % 4096) as u64, ..Default::default() };
+        for c in 0..4 {
+            let (col, row) = (20 + 60 * c as u8, 30 + 50 * c as u8 + (i % 7) as u8);
+            let toa = time + 3_000_000 + 7_000_000 * c + 1_562 * i;
+            for j in 0..(1 + (c + i) % 6) {
+                let tot = (25 * (1 + 3 * j + c + i % 11)) as u32;
+                pulse.add_hit(toa + 1_563 * j, tot, col + (j % 3) as u8, row + (j / 3) as u8);
+            }
+        }
+        pulse
+    }
+
+    #[test]
+    fn centroid_cluster_compress_decodes_to_its_centroids() {
+        let path = std::env::temp_dir().join(format!("centroid_regression_{}.tpx3", std::process::id()));
+        // the reader only learns a pulse's time from the tdc that precedes it, so the first pulse
+        // of both the raw and the compressed file is skipped
+        let lead = pulse::Pulse { time: 500_000_000, ..Default::default() };
+        let mut bytes = lead.to_tdc_packet().to_le_bytes().to_vec();
+        bytes.extend((1..=1200).flat_map(|i| raw_pulse(i).to_bytes()));
+        std::fs::write(&path, bytes).unwrap();
+        centroid_cluster_compress(&path).unwrap();
+
+        let expected = reader::TPX3Reader::new(&path).unwrap().skip(2).map(|mut p| {
+            p.label_hits();
+            p.centroid()
+        });
+        let decoded: Vec<pulse::Pulse> =
+            reader::TPX3Reader::new(&path.with_extension("tpx3c")).unwrap().skip(1).collect();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("tpx3c")).unwrap();
+
+        let fields = |h: &Hit| (h.toa, h.tot, h.col, h.row, h.size.max(1), h.col_offset, h.row_offset);
+        let mut count = 0;
+        for (want, got) in expected.zip(&decoded) {
+            assert_eq!((want.time, want.triggers), (got.time, got.triggers));
+            let want_hits: Vec<_> = want.hits.iter().map(fields).collect();
+            let got_hits: Vec<_> = got.hits.iter().map(fields).collect();
+            assert_eq!(want_hits, got_hits, "pulse at {} ps", want.time);
+            count += 1;
+        }
+        assert_eq!((count, decoded.len()), (1199, 1199));
+    }
+
+    #[test]
+    fn save_scaled_png_records_the_scaling() {
+        let path = std::env::temp_dir().join(format!("two_grids_scaled_{}.png", std::process::id()));
+        let scaling = image::Scaling::Percentile { low: 1.0, high: 99.0 };
+        save_scaled_png(&[0u32, 5, 10, 20, 40, 80], 3, 2, &path, scaling).unwrap();
+        let reader = png::Decoder::new(std::fs::File::open(&path).unwrap()).read_info().unwrap();
+        let text: Vec<(&str, &str)> =
+            reader.info().uncompressed_latin1_text.iter().map(|t| (t.keyword.as_str(), t.text.as_str())).collect();
+        let expected = [("scaling", "Percentile { low: 1.0, high: 99.0 }"), ("range", "5 80"), ("full_scale", "65530")];
+        assert_eq!(text, expected);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrate_tpx3c_rewrites_version_1_blob_packets() {
+        let path = std::env::temp_dir().join(format!("migration_{}.tpx3c", std::process::id()));
+        let pulses: Vec<pulse::Pulse> = (1..=50).map(|i| {
+            let mut p = raw_pulse(i);
+            p.label_hits();
+            p.centroid()
+        }).collect();
+        let legacy: Vec<u8> = pulses.iter().flat_map(|p| {
+            let mut packets = vec![p.to_tdc_packet()];
+            for h in p.hits.iter() {
+                packets.push(h.to_hit_packet());
+                if h.size > 1 {
+                    // version 1 kept the offsets and size where they are but lost the ToT
+                    let blob = h.to_blob_packet() & 0x00FF_FF00_0000_FFFF;
+                    packets.push((hit::BLOB_HEADER_V1 as u64) << 56 | blob);
+                }
+            }
+            packets.into_iter().flat_map(|p| p.to_le_bytes())
+        }).collect();
+        std::fs::write(&path, legacy).unwrap();
+        let blobs = pulses.iter().flat_map(|p| &p.hits).filter(|h| h.size > 1).count();
+        assert_eq!(migrate_tpx3c(&path).unwrap(), blobs);
+
+        let migrated_path = path.with_file_name(format!("migration_{}_v2.tpx3c", std::process::id()));
+        let fields = |h: &Hit| (h.toa, h.tot, h.col, h.row, h.size, h.col_offset, h.row_offset);
+        let before: Vec<Vec<_>> = reader::TPX3Reader::new(&path).unwrap()
+            .map(|p| p.hits.iter().map(fields).collect()).collect();
+        let after: Vec<Vec<_>> = reader::TPX3Reader::new(&migrated_path).unwrap()
+            .map(|p| p.hits.iter().map(fields).collect()).collect();
+        let rewritten = std::fs::read(&migrated_path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&migrated_path).unwrap();
+        assert_eq!(before, after);
+        assert!(rewritten.chunks(8).all(|b| b[7] != hit::BLOB_HEADER_V1));
+    }
+}
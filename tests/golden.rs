@@ -0,0 +1,334 @@
+//! Golden-output regression test for the full processing pipeline.
+//!
+//! A synthetic stand-in for two_grids.tpx3c (four serpentine stage passes with three ion species
+//! in distinct spatial patterns, background ions, noise and one hot pixel) is generated
+//! deterministically and run through `pipeline::run` with two regions of interest, a mass list and
+//! color images. The TIC, ion and color images, the full and region of interest spectrum csvs, the
+//! peak table and the peak list are then compared against the files stored in `tests/golden`; the
+//! OME-TIFF stack is only checked for one page per image.
+//!
+//! `GOLDEN_TOLERANCE=0.01 cargo test --test golden` allows values to differ by a fraction (here
+//! 1%) of full scale for image pixels, of the highest bin for spectra and of the apex time and
+//! area for peaks.
+//! `REGENERATE_GOLDENS=1 cargo test --test golden` rewrites the golden files from the current
+//! pipeline output; review the resulting diff before committing it.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use two_grids_script::{
+    config::RunConfig,
+    hit::Hit,
+    image, pipeline,
+    pulse::Pulse,
+    render::{Colormap, Overlay, Presentation},
+    roi::{Roi, Shape},
+};
+
+const PULSE_LENGTH: i64 = 56_675_000; // ps, on the 25 ns tdc clock
+const PULSES_PER_ROW: i64 = 300;
+const ROWS: i64 = 4; // the last pass is not used for coordinates
+const FIRST_ROW: i64 = 2_000_000_000_000; // ps
+const ROW_PERIOD: i64 = 31_000_000_000_000; // ps, > 30 s so each pass is detected
+const PEAKS: [i64; 3] = [20_000_000, 26_000_000, 31_000_000]; // tof in ps
+const PEAK_SIGMA: f64 = 12_000.0; // ps
+const IONS_PER_PEAK: u64 = 167; // per pulse
+const HOT_PIXEL: (u8, u8) = (200, 17);
+const BASE_NAME: &str = "synthetic";
+
+/// xorshift64* so the synthetic data does not depend on the version of any random crate
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: u64) -> u64 { self.next() % n }
+
+    fn unit(&mut self) -> f64 { (self.next() >> 11) as f64 / (1u64 << 53) as f64 }
+
+    /// approximately normal from the sum of twelve uniforms
+    fn normal(&mut self) -> f64 { (0..12).map(|_| self.unit()).sum::<f64>() - 6.0 }
+}
+
+/// species 0 only lands on the left half of the detector, species 1 on horizontal bars and
+/// species 2 everywhere
+fn species_visible(species: usize, col: u8, row: u8) -> bool {
+    match species {
+        0 => col < 128,
+        1 => row % 64 < 32,
+        _ => true,
+    }
+}
+
+fn ion(rng: &mut Rng, toa: i64, col: u8, row: u8) -> Hit {
+    let size = 2 + rng.below(4) as u16;
+    Hit {
+        size,
+        col_offset: rng.below(256) as u8,
+        row_offset: rng.below(256) as u8,
+        ..Hit::new(0, toa, 25 * (4 + rng.below(20) as u32) * size as u32, col, row)
+    }
+}
+
+fn synthetic_pulse(rng: &mut Rng, time: i64) -> Pulse {
+    let mut pulse = Pulse { time, ..Default::default() };
+    for (species, &peak) in PEAKS.iter().enumerate() {
+        for _ in 0..IONS_PER_PEAK {
+            let (col, row) = loop {
+                let (col, row) = (rng.below(256) as u8, rng.below(256) as u8);
+                if species_visible(species, col, row) {
+                    break (col, row);
+                }
+            };
+            let tof = peak + (rng.normal() * PEAK_SIGMA) as i64;
+            pulse.hits.push(ion(rng, time + tof, col, row));
+        }
+    }
+    for _ in 0..2 {
+        let tof = rng.below(PULSE_LENGTH as u64 - 1_000_000) as i64;
+        let (col, row) = (rng.below(256) as u8, rng.below(256) as u8);
+        pulse.hits.push(ion(rng, time + tof, col, row));
+    }
+    let tof = rng.below(PULSE_LENGTH as u64 - 1_000_000) as i64;
+    pulse.add_hit(time + tof, 100, rng.below(256) as u8, rng.below(256) as u8);
+    pulse.add_hit(time + 1_000_000, 100, HOT_PIXEL.0, HOT_PIXEL.1);
+    pulse.hits.sort_by_key(|h| h.toa);
+    pulse
+}
+
+fn write_synthetic_tpx3c(path: &Path) {
+    let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+    let mut bytes = vec![];
+    for row in 0..ROWS {
+        let start = FIRST_ROW + row * ROW_PERIOD;
+        for i in 0..PULSES_PER_ROW {
+            let mut pulse = synthetic_pulse(&mut rng, start + i * PULSE_LENGTH);
+            if i == PULSES_PER_ROW - 1 {
+                // noise while the stage turns around keeps the hit rollover counter in step
+                let end = pulse.time + PULSE_LENGTH;
+                for s in 1..30 {
+                    pulse.add_hit(end + s * 1_000_000_000_000, 100, rng.below(256) as u8, 9);
+                }
+            }
+            bytes.extend(pulse.to_bytes());
+        }
+    }
+    std::fs::write(path, bytes).unwrap();
+}
+
+fn config() -> image::Config {
+    let mut config = image::Config {
+        width: 0.5,
+        height: 0.3,
+        pixels_per_mm: 200.0,
+        rotation: 280.5 / 100.0,
+        scale_x: 1.0,
+        scale_y: 1.0,
+        camera_fov: 395.0 / 256.0,
+        tof_pulse_length: PULSE_LENGTH,
+        ..Default::default()
+    };
+    config.update();
+    config
+}
+
+/// the left half of the image and a triangle in its lower right
+fn rois() -> Vec<Roi> {
+    let (cols, rows) = (config().cols() as f64, config().rows() as f64);
+    vec![
+        Roi {
+            name: String::from("left"),
+            shape: Shape::Rectangle { col: 0.0, row: 0.0, width: cols / 2.0, height: rows },
+        },
+        Roi {
+            name: String::from("triangle"),
+            shape: Shape::Polygon { vertices: vec![(cols, rows / 2.0), (cols, rows), (cols / 2.0, rows)] },
+        },
+    ]
+}
+
+fn golden_dir() -> PathBuf { Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden") }
+
+/// the pipeline outputs that are compared (the plotly html embeds random ids and is skipped)
+fn outputs(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .filter_map(|e| e.ok()?.file_name().into_string().ok())
+        .filter(|n| n.starts_with(BASE_NAME) && (n.ends_with(".png") || n.ends_with(".csv")))
+        .collect();
+    names.sort();
+    names
+}
+
+fn read_png(path: &Path) -> (u32, u32, Vec<u16>) {
+    let decoder = png::Decoder::new(std::fs::File::open(path).unwrap());
+    let mut reader = decoder.read_info().unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).unwrap();
+    let bytes = &buf[..info.buffer_size()];
+    let samples: Vec<u16> = match info.bit_depth {
+        png::BitDepth::Sixteen => bytes.chunks(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect(),
+        _ => bytes.iter().map(|&b| b as u16 * 257).collect(), // 8 bit color images
+    };
+    (info.width, info.height, samples)
+}
+
+fn read_pairs(path: &Path) -> Vec<(i64, f64)> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            let mut fields = l.split(',');
+            let first = fields.next().unwrap().trim().parse().unwrap();
+            (first, fields.next().map_or(0.0, |v| v.trim().parse().unwrap()))
+        })
+        .collect()
+}
+
+/// the number of pages of a tiff
+fn count_pages(path: &Path) -> usize {
+    let mut decoder = tiff::decoder::Decoder::new(std::fs::File::open(path).unwrap()).unwrap();
+    let mut pages = 1;
+    while decoder.more_images() {
+        decoder.next_image().unwrap();
+        pages += 1;
+    }
+    pages
+}
+
+fn within(a: f64, b: f64, allowed: f64) -> bool { (a - b).abs() <= allowed }
+
+fn compare_png(name: &str, got: &Path, want: &Path, tolerance: f64, errors: &mut Vec<String>) {
+    let ((gw, gh, gp), (ww, wh, wp)) = (read_png(got), read_png(want));
+    if (gw, gh) != (ww, wh) {
+        return errors.push(format!("{}: size {}x{} instead of {}x{}", name, gw, gh, ww, wh));
+    }
+    let allowed = tolerance * u16::MAX as f64;
+    let bad = gp.iter().zip(&wp).filter(|(&g, &w)| !within(g as f64, w as f64, allowed)).count();
+    if bad > 0 {
+        errors.push(format!("{}: {} of {} pixels differ", name, bad, gp.len()));
+    }
+}
+
+/// compares spectra bin by bin so that a bin appearing in only one of them counts as zero
+fn compare_spectrum(name: &str, got: &[(i64, f64)], want: &[(i64, f64)], tol: f64, errors: &mut Vec<String>) {
+    let (got, want): (BTreeMap<_, _>, BTreeMap<_, _>) =
+        (got.iter().copied().collect(), want.iter().copied().collect());
+    let allowed = tol * want.values().copied().fold(0.0, f64::max);
+    let bad: Vec<&i64> = got
+        .keys()
+        .chain(want.keys())
+        .filter(|t| !within(*got.get(t).unwrap_or(&0.0), *want.get(t).unwrap_or(&0.0), allowed))
+        .collect();
+    if let Some(first) = bad.first() {
+        errors.push(format!("{}: {} bins differ, starting at {}", name, bad.len(), first));
+    }
+}
+
+/// compares csv tables with a header row field by field, relative to each wanted value
+fn compare_table(name: &str, got: &Path, want: &Path, tol: f64, errors: &mut Vec<String>) {
+    let read = |path: &Path| -> Vec<Vec<f64>> {
+        let text = std::fs::read_to_string(path).unwrap();
+        text.lines().skip(1).map(|l| l.split(',').map(|v| v.trim().parse().unwrap()).collect()).collect()
+    };
+    let (got, want) = (read(got), read(want));
+    if got.len() != want.len() {
+        return errors.push(format!("{}: {} rows instead of {}", name, got.len(), want.len()));
+    }
+    for (row, (g, w)) in got.iter().zip(&want).enumerate() {
+        if g.len() != w.len() || g.iter().zip(w).any(|(g, w)| !within(*g, *w, tol * w.abs())) {
+            return errors.push(format!("{}: row {} is {:?} instead of {:?}", name, row, g, w));
+        }
+    }
+}
+
+fn compare_pairs(name: &str, got: &[(i64, f64)], want: &[(i64, f64)], tol: f64, errors: &mut Vec<String>) {
+    if got.len() != want.len() {
+        return errors.push(format!("{}: {} rows instead of {}", name, got.len(), want.len()));
+    }
+    for (row, ((gt, gv), (wt, wv))) in got.iter().zip(want).enumerate() {
+        let (gt, wt) = (*gt as f64, *wt as f64);
+        if !within(gt, wt, tol * wt.abs()) || !within(*gv, *wv, tol * wv.abs()) {
+            return errors.push(format!("{}: row {} is {},{} instead of {},{}", name, row, gt, gv, wt, wv));
+        }
+    }
+}
+
+#[test]
+fn pipeline_matches_golden_output() {
+    let dir = std::env::temp_dir().join(format!("two_grids_golden_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join(BASE_NAME).with_extension("tpx3c");
+    write_synthetic_tpx3c(&input);
+    let mass_list = dir.join("mass_list.csv");
+    std::fs::write(&mass_list, "# m/z, tolerance, label\n60.9, 2000 ppm, bars\n93.3, 0.1\n").unwrap();
+    let presentation = Presentation {
+        colormap: Some(Colormap::Viridis),
+        overlays: vec![Overlay {
+            name: String::from("overlay"),
+            red: Some(String::from("31.0mz")),
+            green: Some(String::from("bars")),
+            blue: None,
+        }],
+        ..Default::default()
+    };
+    let run_config = RunConfig {
+        image: config(),
+        rois: rois(),
+        mass_list: Some(mass_list),
+        presentation,
+        ome_tiff: true,
+        ..Default::default()
+    };
+    let peaks = pipeline::run(&input, &run_config).unwrap();
+    let peak_list: String = peaks.iter().map(|p| format!("{},{:.1}\n", p.apex, p.area)).collect();
+    let peak_file = format!("{}_peaks.txt", BASE_NAME);
+
+    let golden = golden_dir();
+    if std::env::var_os("REGENERATE_GOLDENS").is_some() {
+        std::fs::create_dir_all(&golden).unwrap();
+        for stale in outputs(&golden) {
+            std::fs::remove_file(golden.join(stale)).unwrap();
+        }
+        for name in outputs(&dir) {
+            std::fs::copy(dir.join(&name), golden.join(&name)).unwrap();
+        }
+        std::fs::write(golden.join(&peak_file), peak_list).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        return;
+    }
+
+    let tolerance: f64 = std::env::var("GOLDEN_TOLERANCE").map_or(0.0, |t| t.parse().unwrap());
+    let mut errors = vec![];
+    let (got, want) = (outputs(&dir), outputs(&golden));
+    if got != want {
+        errors.push(format!("output files {:?} instead of {:?}", got, want));
+    }
+    for name in got.iter().filter(|n| want.contains(n)) {
+        let (g, w) = (dir.join(name), golden.join(name));
+        if name.ends_with(".png") {
+            compare_png(name, &g, &w, tolerance, &mut errors);
+        } else if name.ends_with("_report_peaks.csv") || name.ends_with("_report_roi_spectra.csv") {
+            compare_table(name, &g, &w, tolerance, &mut errors);
+        } else {
+            compare_spectrum(name, &read_pairs(&g), &read_pairs(&w), tolerance, &mut errors);
+        }
+    }
+    let stack = count_pages(&dir.join(format!("{}_stack.ome.tif", BASE_NAME)));
+    if stack != 1 + peaks.len() + 2 {
+        let expected = peaks.len() + 2; // found peaks and mass list entries
+        errors.push(format!("the OME-TIFF stack has {} pages instead of the TIC and {} ion images", stack, expected));
+    }
+    let got_peaks: Vec<(i64, f64)> = peaks.iter().map(|p| (p.apex, (p.area * 10.0).round() / 10.0)).collect();
+    compare_pairs(&peak_file, &got_peaks, &read_pairs(&golden.join(&peak_file)), tolerance, &mut errors);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(errors.is_empty(), "pipeline output differs from tests/golden:\n{}", errors.join("\n"));
+}